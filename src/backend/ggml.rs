@@ -1,28 +1,52 @@
 use crate::{
-    error,
+    cors_allow_headers, error, retrieval,
     utils::{gen_chat_id, print_log_begin_separator, print_log_end_separator},
-    GLOBAL_RAG_PROMPT, SERVER_INFO,
+    QdrantConfig, QdrantOutageBehavior, API_KEY, CORS_MAX_AGE, GLOBAL_RAG_PROMPT, SERVER_INFO,
 };
 use chat_prompts::{error as ChatPromptsError, MergeRagContext, MergeRagContextPolicy};
 use endpoints::{
-    chat::{ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionUserMessageContent},
-    embeddings::EmbeddingRequest,
+    chat::{
+        ChatCompletionChunk, ChatCompletionChunkChoice, ChatCompletionChunkChoiceDelta,
+        ChatCompletionObject, ChatCompletionObjectChoice, ChatCompletionObjectMessage,
+        ChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionRole,
+        ChatCompletionUserMessageContent, StreamOptions,
+    },
+    common::{FinishReason, Usage},
+    completions::{CompletionObject, CompletionRequest},
+    embeddings::{EmbeddingRequest, EmbeddingsResponse, InputText},
     files::FileObject,
-    rag::{ChunksRequest, ChunksResponse, RagEmbeddingRequest},
+    models::Model,
+    rag::{ChunksRequest, ChunksResponse, RagEmbeddingRequest, RagScoredPoint, RetrieveObject},
 };
-use futures_util::TryStreamExt;
-use hyper::{body::to_bytes, Body, Method, Request, Response};
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use hyper::{body::to_bytes, Body, HeaderMap, Method, Request, Response, Version};
 use multipart::server::{Multipart, ReadEntry, ReadEntryResult};
 use multipart_2021 as multipart;
+use serde::{Deserialize, Serialize};
 use std::{
     fs::{self, File},
     io::{Cursor, Read, Write},
     path::Path,
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime},
 };
 
-/// List all models available.
-pub(crate) async fn models_handler() -> Result<Response<Body>, hyper::Error> {
+/// List the chat and embedding models served by the server, identified by their aliases.
+pub(crate) async fn models_handler(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        return preflight_response();
+    }
+    if req.method() != Method::GET {
+        return error::method_not_allowed(req.method().as_str(), "GET");
+    }
+
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info,
+        None => {
+            return error::internal_server_error("The server info is not set.");
+        }
+    };
+
+    // the models loaded by llama-core, keyed by the model names
     let list_models_response = match llama_core::models::models().await {
         Ok(list_models_response) => list_models_response,
         Err(e) => {
@@ -30,6 +54,35 @@ pub(crate) async fn models_handler() -> Result<Response<Body>, hyper::Error> {
         }
     };
 
+    let data = [
+        &server_info.rag_config.chat_model,
+        &server_info.rag_config.embedding_model,
+    ]
+    .into_iter()
+    .map(|model_config| {
+        let created = list_models_response
+            .data
+            .iter()
+            .find(|model| model.id == model_config.name)
+            .map_or(0, |model| model.created);
+        ModelObject {
+            model: Model {
+                id: model_config.alias.clone(),
+                created,
+                object: String::from("model"),
+                owned_by: String::from("Not specified"),
+            },
+            name: model_config.name.clone(),
+            ty: model_config.ty.clone(),
+            ctx_size: model_config.ctx_size,
+        }
+    })
+    .collect();
+    let list_models_response = RagListModelsResponse {
+        object: String::from("list"),
+        data,
+    };
+
     // serialize response
     let s = match serde_json::to_string(&list_models_response) {
         Ok(s) => s,
@@ -51,28 +104,88 @@ pub(crate) async fn models_handler() -> Result<Response<Body>, hyper::Error> {
     }
 }
 
+/// List of the models served by the server.
+#[derive(Debug, Serialize)]
+struct RagListModelsResponse {
+    object: String,
+    data: Vec<ModelObject>,
+}
+
+/// Model object extended with the configuration of the model.
+#[derive(Debug, Serialize)]
+struct ModelObject {
+    #[serde(flatten)]
+    model: Model,
+    /// Name of the model
+    name: String,
+    /// Type of the model: `chat` or `embedding`
+    #[serde(rename = "type")]
+    ty: String,
+    /// Context size of the model
+    ctx_size: u64,
+}
+
+/// Respond to a CORS preflight request.
+fn preflight_response() -> Result<Response<Body>, hyper::Error> {
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header(
+            "Access-Control-Allow-Headers",
+            cors_allow_headers(API_KEY.get().is_some()),
+        )
+        .header(
+            "Access-Control-Max-Age",
+            CORS_MAX_AGE.get().copied().unwrap_or_default(),
+        )
+        .header("Content-Type", "application/json")
+        .body(Body::empty());
+
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
+}
+
 /// Process a chat-completion request in stream mode and returns a chat-completion response with the answer from the model.
+///
+/// Over HTTP/2, the usage and the generation time are also sent as trailers at the end of the stream.
 async fn chat_completions_stream(
     mut chat_request: ChatCompletionRequest,
+    rag_metadata: RagMetadata,
+    version: Version,
 ) -> Result<Response<Body>, hyper::Error> {
     if chat_request.user.is_none() {
         chat_request.user = Some(gen_chat_id())
     };
     let id = chat_request.user.clone().unwrap();
 
+    let (include_usage, trailers) = prepare_stream_usage(&mut chat_request, version);
+
+    let start = Instant::now();
     match llama_core::chat::chat_completions_stream(&mut chat_request).await {
         Ok(stream) => {
-            let stream = stream.map_err(|e| e.to_string());
+            let stream =
+                limit_stream_bytes(stream.map_err(|e| e.to_string()), max_response_bytes());
 
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "text/event-stream")
-                .header("Cache-Control", "no-cache")
-                .header("Connection", "keep-alive")
-                .header("user", id)
-                .body(Body::wrap_stream(stream));
+            let mut builder = stream_response_builder(id);
+            // the chunks of the stream cannot carry extra fields, so the metadata goes to the headers
+            if let Some(grounding_score) = rag_metadata.grounding_score {
+                builder = builder.header("x-grounding-score", grounding_score.to_string());
+            }
+            if rag_metadata.retrieval_unavailable {
+                builder = builder.header("x-retrieval-unavailable", "true");
+            }
+            let result = match trailers {
+                true => {
+                    let (sender, body) = Body::channel();
+                    tokio::spawn(send_stream(sender, stream, Some(start), include_usage));
+                    builder
+                        .header("Trailer", TRAILER_NAMES.join(", "))
+                        .body(body)
+                }
+                false => builder.body(Body::wrap_stream(stream)),
+            };
 
             match result {
                 Ok(response) => Ok(response),
@@ -83,9 +196,244 @@ async fn chat_completions_stream(
     }
 }
 
+/// Process a chat-completion request in stream mode, sending a `retrieval` event as each retrieval stage starts or ends, ahead of the answer from the model.
+///
+/// The response is returned before the retrieval starts, so that the client is notified while the user query is embedded and searched. Since the status of the response is already sent, a failure afterwards aborts the stream.
+fn chat_completions_stream_with_events(
+    mut chat_request: ChatCompletionRequest,
+    query_text: String,
+    qdrant_config: QdrantConfig,
+    version: Version,
+) -> Result<Response<Body>, hyper::Error> {
+    if chat_request.user.is_none() {
+        chat_request.user = Some(gen_chat_id())
+    };
+    let id = chat_request.user.clone().unwrap();
+
+    let (include_usage, trailers) = prepare_stream_usage(&mut chat_request, version);
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        if let Err(e) = retrieve_context(
+            &mut chat_request,
+            &query_text,
+            &qdrant_config,
+            Some(&mut sender),
+        )
+        .await
+        {
+            println!("    * Failed to retrieve context. {}", e);
+            sender.abort();
+            return;
+        }
+
+        let start = Instant::now();
+        let stream = match llama_core::chat::chat_completions_stream(&mut chat_request).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("    * Failed to generate the stream. {}", e);
+                sender.abort();
+                return;
+            }
+        };
+        let stream = limit_stream_bytes(stream.map_err(|e| e.to_string()), max_response_bytes());
+        send_stream(sender, stream, trailers.then_some(start), include_usage).await;
+    });
+
+    let mut builder = stream_response_builder(id);
+    if trailers {
+        builder = builder.header("Trailer", TRAILER_NAMES.join(", "));
+    }
+
+    match builder.body(body) {
+        Ok(response) => Ok(response),
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
+}
+
+// the response builder with the headers of a stream
+fn stream_response_builder(id: String) -> hyper::http::response::Builder {
+    Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .header("Connection", "keep-alive")
+        .header("user", id)
+}
+
+// the `--max-response-bytes` limit, or no limit
+fn max_response_bytes() -> usize {
+    SERVER_INFO
+        .get()
+        .and_then(|server_info| server_info.rag_config.max_response_bytes)
+        .unwrap_or(usize::MAX)
+}
+
+/// Stop the stream once `max_bytes` bytes are sent, and end it with a chunk whose finish reason is `length`, followed by `data: [DONE]`.
+///
+/// The inner stream is still polled to its end after the cutoff, with its chunks discarded, so that the chat model finishes the generation and resets its state.
+fn limit_stream_bytes<S>(stream: S, max_bytes: usize) -> impl Stream<Item = Result<String, String>>
+where
+    S: Stream<Item = Result<String, String>>,
+{
+    // number of bytes sent so far, or `None` once the stream is stopped
+    stream
+        .scan(Some(0usize), move |sent, chunk| {
+            let item = match (sent.as_mut(), chunk) {
+                // drain the inner stream, unless it fails
+                (None, Ok(_)) => Some(None),
+                (None, Err(_)) => None,
+                (Some(_), Err(e)) => Some(Some(Err(e))),
+                (Some(num_bytes), Ok(chunk)) => {
+                    *num_bytes = num_bytes.saturating_add(chunk.len());
+                    match *num_bytes > max_bytes && chunk.trim() != "data: [DONE]" {
+                        true => {
+                            *sent = None;
+                            println!(
+                                "    * The stream is stopped after reaching the limit of {} bytes.",
+                                max_bytes
+                            );
+                            Some(Some(Ok(length_finish_chunk(&chunk))))
+                        }
+                        false => Some(Some(Ok(chunk))),
+                    }
+                }
+            };
+
+            futures_util::future::ready(item)
+        })
+        .filter_map(futures_util::future::ready)
+}
+
+// the final events of a stream stopped by the byte limit; `chunk` is the first chunk that is not sent
+fn length_finish_chunk(chunk: &str) -> String {
+    let done = "data: [DONE]\n\n";
+    let chunk = match chunk
+        .trim()
+        .strip_prefix("data: ")
+        .and_then(|data| serde_json::from_str::<ChatCompletionChunk>(data).ok())
+    {
+        Some(chunk) => chunk,
+        None => return done.to_string(),
+    };
+
+    let finish_chunk = ChatCompletionChunk {
+        choices: vec![ChatCompletionChunkChoice {
+            index: 0,
+            delta: ChatCompletionChunkChoiceDelta {
+                role: None,
+                content: None,
+                function_call: None,
+                tool_calls: None,
+            },
+            logprobs: None,
+            finish_reason: Some(FinishReason::length),
+        }],
+        usage: None,
+        ..chunk
+    };
+    match serde_json::to_string(&finish_chunk) {
+        Ok(data) => format!("data: {}\n\n{}", data, done),
+        Err(_) => done.to_string(),
+    }
+}
+
+// names of the trailers sent at the end of a stream over HTTP/2
+const TRAILER_NAMES: [&str; 4] = [
+    "x-usage-prompt-tokens",
+    "x-usage-completion-tokens",
+    "x-usage-total-tokens",
+    "x-generation-time-ms",
+];
+
+/// Request the usage chunk of a stream if the usage is sent as trailers, which is the case over HTTP/2. Returns whether the client requested the usage chunk, and whether the trailers are sent.
+fn prepare_stream_usage(
+    chat_request: &mut ChatCompletionRequest,
+    version: Version,
+) -> (bool, bool) {
+    // the usage chunk is required to fill the trailers, but only sent to the client if requested
+    let include_usage = chat_request
+        .stream_options
+        .as_ref()
+        .and_then(|stream_options| stream_options.include_usage)
+        .unwrap_or(false);
+    let trailers = version == Version::HTTP_2;
+    if trailers {
+        chat_request.stream_options = Some(StreamOptions {
+            include_usage: Some(true),
+        });
+    }
+
+    (include_usage, trailers)
+}
+
+/// Forward the stream to the body of the response through the sender. If `start` is given, the usage and the time elapsed since `start` are sent as trailers at the end.
+///
+/// The usage chunk is only forwarded if `include_usage` is set, since it may be requested by the server for the trailers only.
+async fn send_stream<S>(
+    mut sender: hyper::body::Sender,
+    stream: S,
+    start: Option<Instant>,
+    include_usage: bool,
+) where
+    S: Stream<Item = Result<String, String>>,
+{
+    let mut stream = Box::pin(stream);
+    let mut usage: Option<Usage> = None;
+    while let Some(chunk) = stream.next().await {
+        match chunk {
+            Ok(chunk) => {
+                // keep the usage from the usage chunk
+                if let Some(data) = chunk.trim().strip_prefix("data: ") {
+                    if let Ok(chunk) = serde_json::from_str::<ChatCompletionChunk>(data) {
+                        if chunk.usage.is_some() {
+                            usage = chunk.usage;
+                            if chunk.choices.is_empty() && !include_usage {
+                                continue;
+                            }
+                        }
+                    }
+                }
+
+                if sender.send_data(chunk.into()).await.is_err() {
+                    // the client has gone
+                    return;
+                }
+            }
+            Err(e) => {
+                println!("    * Failed to generate the stream. {}", e);
+                sender.abort();
+                return;
+            }
+        }
+    }
+
+    let start = match start {
+        Some(start) => start,
+        None => return,
+    };
+    let mut trailers = HeaderMap::new();
+    if let Some(usage) = usage {
+        trailers.insert(TRAILER_NAMES[0], usage.prompt_tokens.into());
+        trailers.insert(TRAILER_NAMES[1], usage.completion_tokens.into());
+        trailers.insert(TRAILER_NAMES[2], usage.total_tokens.into());
+    }
+    trailers.insert(
+        TRAILER_NAMES[3],
+        (start.elapsed().as_millis() as u64).into(),
+    );
+
+    if let Err(e) = sender.send_trailers(trailers).await {
+        println!("    * Failed to send the trailers. {}", e);
+    }
+}
+
 /// Process a chat-completion request and returns a chat-completion response with the answer from the model.
 async fn chat_completions(
     mut chat_request: ChatCompletionRequest,
+    rag_metadata: RagMetadata,
 ) -> Result<Response<Body>, hyper::Error> {
     if chat_request.user.is_none() {
         chat_request.user = Some(gen_chat_id())
@@ -94,55 +442,301 @@ async fn chat_completions(
 
     match llama_core::chat::chat_completions(&mut chat_request).await {
         Ok(chat_completion_object) => {
-            // serialize chat completion object
-            let s = match serde_json::to_string(&chat_completion_object) {
-                Ok(s) => s,
-                Err(e) => {
-                    return error::internal_server_error(format!(
-                        "Fail to serialize chat completion object. {}",
-                        e
-                    ));
-                }
-            };
+            chat_completion_response(chat_completion_object, rag_metadata, id)
+        }
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
+}
 
-            // return response
-            let result = Response::builder()
-                .header("Access-Control-Allow-Origin", "*")
-                .header("Access-Control-Allow-Methods", "*")
-                .header("Access-Control-Allow-Headers", "*")
-                .header("Content-Type", "application/json")
-                .header("user", id)
-                .body(Body::from(s));
+/// Build the response carrying the chat completion object extended with the RAG metadata.
+fn chat_completion_response(
+    chat_completion_object: ChatCompletionObject,
+    rag_metadata: RagMetadata,
+    id: String,
+) -> Result<Response<Body>, hyper::Error> {
+    let timing_headers = SERVER_INFO
+        .get()
+        .is_some_and(|server_info| server_info.rag_config.timing_headers);
+
+    build_chat_completion_response(
+        chat_completion_object,
+        rag_metadata,
+        id,
+        timing_headers,
+        max_response_bytes(),
+    )
+}
 
-            match result {
-                Ok(response) => Ok(response),
-                Err(e) => error::internal_server_error(e.to_string()),
-            }
+// the response of `chat_completion_response`, with the `--timing-headers` and `--max-response-bytes` options given
+fn build_chat_completion_response(
+    chat_completion_object: ChatCompletionObject,
+    rag_metadata: RagMetadata,
+    id: String,
+    timing_headers: bool,
+    max_response_bytes: usize,
+) -> Result<Response<Body>, hyper::Error> {
+    let prompt_tokens = chat_completion_object.usage.prompt_tokens;
+    let rag_chat_completion_object = RagChatCompletionObject {
+        object: chat_completion_object,
+        metadata: rag_metadata,
+    };
+
+    // serialize chat completion object
+    let s = match serialize_within(rag_chat_completion_object, max_response_bytes) {
+        Ok(s) => s,
+        Err(e) => {
+            return error::internal_server_error(format!(
+                "Fail to serialize chat completion object. {}",
+                e
+            ));
         }
+    };
+
+    // return response
+    let mut builder = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .header("user", id);
+    if timing_headers {
+        // number of tokens of the final prompt, counted by the tokenizer of the chat model
+        builder = builder.header("X-Prompt-Tokens", prompt_tokens.to_string());
+    }
+    let result = builder.body(Body::from(s));
+
+    match result {
+        Ok(response) => Ok(response),
         Err(e) => error::internal_server_error(e.to_string()),
     }
 }
 
+/// Send the text of the last user message verbatim to the chat model, bypassing the prompt template and RAG.
+///
+/// Raw prompts are only accepted if the server is started with `--allow-raw-prompt`. Stream mode is not supported.
+async fn raw_completion(
+    mut chat_request: ChatCompletionRequest,
+) -> Result<Response<Body>, hyper::Error> {
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info,
+        None => {
+            return error::internal_server_error("The server info is not set.");
+        }
+    };
+
+    if !server_info.rag_config.allow_raw_prompt {
+        return error::bad_request(
+            "Raw prompts are not allowed. Start the server with the `--allow-raw-prompt` option to enable them.",
+        );
+    }
+    if chat_request.stream == Some(true) {
+        return error::bad_request("Stream mode is not supported for raw prompts.");
+    }
+    if let Err(e) = check_model_field(
+        chat_request.model.as_deref(),
+        server_info.rag_config.require_model_field,
+    ) {
+        return error::bad_request(e);
+    }
+    if let Err(e) = check_raw_prompt_fields(&chat_request) {
+        return error::bad_request(e);
+    }
+
+    let id = record_end_user(&mut chat_request.user);
+    let completion_request = match raw_completion_request(&chat_request) {
+        Ok(completion_request) => completion_request,
+        Err(e) => return error::bad_request(e),
+    };
+
+    println!("\n[+] Answer the raw prompt without prompt template and RAG ...");
+
+    let completion_object = match llama_core::completions::completions(&completion_request).await {
+        Ok(completion_object) => completion_object,
+        Err(e) => return error::internal_server_error(e.to_string()),
+    };
+
+    // reply in the shape of a chat completion, as the client sent a chat completion request
+    chat_completion_response(
+        raw_chat_completion_object(completion_object),
+        RagMetadata::default(),
+        id,
+    )
+}
+
+/// Build the completion request of a raw prompt, whose prompt is the text of the last user message as is.
+fn raw_completion_request(
+    chat_request: &ChatCompletionRequest,
+) -> Result<CompletionRequest, String> {
+    let prompt = match chat_request.messages.last() {
+        Some(ChatCompletionRequestMessage::User(user_message)) => match user_message.content() {
+            ChatCompletionUserMessageContent::Text(text) => text.clone(),
+            _ => return Err("The last message must be a text content user message".to_string()),
+        },
+        _ => return Err("The last message must be a user message".to_string()),
+    };
+
+    // llama-core only reads the model and the prompt of a completion request
+    Ok(CompletionRequest {
+        model: chat_request.model.clone(),
+        prompt: vec![prompt],
+        best_of: None,
+        echo: None,
+        frequency_penalty: None,
+        logit_bias: None,
+        logprobs: None,
+        max_tokens: None,
+        n: None,
+        presence_penalty: None,
+        stop: None,
+        stream: None,
+        suffix: None,
+        temperature: None,
+        top_p: None,
+        user: chat_request.user.clone(),
+    })
+}
+
+/// Convert the completion object of a raw prompt into a chat completion object, keeping the generated text as is.
+fn raw_chat_completion_object(completion_object: CompletionObject) -> ChatCompletionObject {
+    ChatCompletionObject {
+        id: completion_object.id,
+        object: String::from("chat.completion"),
+        created: completion_object.created,
+        model: completion_object.model,
+        choices: completion_object
+            .choices
+            .into_iter()
+            .map(|choice| ChatCompletionObjectChoice {
+                index: choice.index,
+                message: ChatCompletionObjectMessage {
+                    role: ChatCompletionRole::Assistant,
+                    content: choice.text,
+                    function_call: None,
+                },
+                finish_reason: choice.finish_reason,
+            })
+            .collect(),
+        usage: completion_object.usage,
+    }
+}
+
+/// Check that a raw prompt request sets none of the sampling fields, which llama-core ignores for completion requests. Rejecting them is better than silently generating with other settings than the requested ones.
+fn check_raw_prompt_fields(chat_request: &ChatCompletionRequest) -> Result<(), String> {
+    let fields = [
+        ("temperature", chat_request.temperature.is_some()),
+        ("top_p", chat_request.top_p.is_some()),
+        ("n_choice", chat_request.n_choice.is_some()),
+        ("stop", chat_request.stop.is_some()),
+        ("max_tokens", chat_request.max_tokens.is_some()),
+        ("presence_penalty", chat_request.presence_penalty.is_some()),
+        (
+            "frequency_penalty",
+            chat_request.frequency_penalty.is_some(),
+        ),
+        ("logit_bias", chat_request.logit_bias.is_some()),
+    ];
+    let unsupported: Vec<String> = fields
+        .iter()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(name, _)| format!("`{}`", name))
+        .collect();
+
+    match unsupported.is_empty() {
+        true => Ok(()),
+        false => Err(format!(
+            "Raw prompts do not support the {} field(s).",
+            unsupported.join(", ")
+        )),
+    }
+}
+
+/// Serialize the chat completion object, truncating it to at most `max_bytes` bytes if possible.
+///
+/// The RAG metadata is dropped first, and then the content of the choices is cut short, starting from the last choice. A truncated object carries `x_response_truncated: true`.
+fn serialize_within(
+    mut object: RagChatCompletionObject,
+    max_bytes: usize,
+) -> serde_json::Result<String> {
+    let s = serde_json::to_string(&object)?;
+    if s.len() <= max_bytes {
+        return Ok(s);
+    }
+
+    println!(
+        "    * The response of {} bytes is truncated to the limit of {} bytes.",
+        s.len(),
+        max_bytes
+    );
+
+    object.metadata = RagMetadata {
+        response_truncated: true,
+        ..Default::default()
+    };
+    let mut s = serde_json::to_string(&object)?;
+
+    while s.len() > max_bytes {
+        let choice = match object
+            .object
+            .choices
+            .iter_mut()
+            .rev()
+            .find(|choice| !choice.message.content.is_empty())
+        {
+            Some(choice) => choice,
+            // nothing left to truncate
+            None => break,
+        };
+
+        // escaping may make the serialized content longer than the content, so retry until it fits
+        let content = &mut choice.message.content;
+        let mut len = content.len().saturating_sub(s.len() - max_bytes);
+        while !content.is_char_boundary(len) {
+            len -= 1;
+        }
+        content.truncate(len);
+        choice.finish_reason = FinishReason::length;
+
+        s = serde_json::to_string(&object)?;
+    }
+
+    Ok(s)
+}
+
 /// Compute embeddings for the input text and return the embeddings object.
 pub(crate) async fn embeddings_handler(
     mut req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
-    // parse request
-    let body_bytes = to_bytes(req.body_mut()).await?;
-    let mut embedding_request: EmbeddingRequest = match serde_json::from_slice(&body_bytes) {
-        Ok(embedding_request) => embedding_request,
-        Err(e) => {
-            return error::bad_request(format!("Fail to parse embedding request: {msg}", msg = e));
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info,
+        None => {
+            return error::internal_server_error("The server info is not set.");
         }
     };
 
-    if embedding_request.user.is_none() {
-        embedding_request.user = Some(gen_chat_id())
-    };
-    let id = embedding_request.user.clone().unwrap();
+    // parse request
+    let body_bytes = to_bytes(req.body_mut()).await?;
+    let mut embedding_request =
+        match parse_embedding_request(&body_bytes, server_info.rag_config.require_model_field) {
+            Ok(embedding_request) => embedding_request,
+            Err(e) => return error::bad_request(e),
+        };
+
+    let id = record_end_user(&mut embedding_request.user);
+
+    if let Err(e) = check_embedding_inputs(
+        &embedding_request.input,
+        server_info.rag_config.max_embedding_inputs,
+    ) {
+        return error::bad_request(e);
+    }
 
     println!("\n[+] Running embeddings handler ...");
-    match llama_core::embeddings::embeddings(&embedding_request).await {
+    match compute_embeddings(
+        embedding_request,
+        server_info.rag_config.embedding_batch_size,
+    )
+    .await
+    {
         Ok(embedding_response) => {
             // serialize embedding object
             match serde_json::to_string(&embedding_response) {
@@ -166,10 +760,25 @@ pub(crate) async fn embeddings_handler(
                 )),
             }
         }
-        Err(e) => error::internal_server_error(e.to_string()),
+        Err(e) => error::internal_server_error(e),
     }
 }
 
+/// Parse the body of an embedding request. The `model` field is required; with `--require-model-field`, a request without it is rejected with an explicit message.
+fn parse_embedding_request(
+    body: &[u8],
+    require_model_field: bool,
+) -> Result<EmbeddingRequest, String> {
+    let value: serde_json::Value = serde_json::from_slice(body)
+        .map_err(|e| format!("Fail to parse embedding request: {msg}", msg = e))?;
+    if require_model_field && value.get("model").is_none() {
+        return Err("The `model` field is required.".to_string());
+    }
+
+    serde_json::from_value(value)
+        .map_err(|e| format!("Fail to parse embedding request: {msg}", msg = e))
+}
+
 /// Compute embeddings for document chunks and persist them in the specified Qdrant server.
 ///
 /// Note that the body of the request is deserialized to a `RagEmbeddingRequest` instance.
@@ -272,6 +881,86 @@ pub(crate) async fn _rag_doc_chunks_to_embeddings2_handler(
     }
 }
 
+/// Compute the embeddings of the inputs in sub-batches of at most `batch_size` inputs, and merge them into a single response.
+async fn compute_embeddings(
+    embedding_request: EmbeddingRequest,
+    batch_size: usize,
+) -> Result<EmbeddingsResponse, String> {
+    let inputs = match &embedding_request.input {
+        InputText::Array(inputs) if inputs.len() > batch_size => inputs.clone(),
+        _ => {
+            return llama_core::embeddings::embeddings(&embedding_request)
+                .await
+                .map_err(|e| e.to_string());
+        }
+    };
+
+    let mut embedding_response: Option<EmbeddingsResponse> = None;
+    for (idx, batch) in inputs.chunks(batch_size).enumerate() {
+        println!(
+            "    * Computing embeddings for inputs {} to {} of {}",
+            idx * batch_size,
+            idx * batch_size + batch.len() - 1,
+            inputs.len()
+        );
+
+        let batch_request = EmbeddingRequest {
+            input: batch.into(),
+            ..embedding_request.clone()
+        };
+        let batch_response = llama_core::embeddings::embeddings(&batch_request)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        embedding_response = Some(merge_embeddings(
+            embedding_response,
+            batch_response,
+            (idx * batch_size) as u64,
+        ));
+    }
+
+    embedding_response.ok_or_else(|| "No embeddings returned".to_string())
+}
+
+/// Check that the number of inputs of an embedding request does not exceed `max_inputs`.
+fn check_embedding_inputs(input: &InputText, max_inputs: usize) -> Result<(), String> {
+    let num_inputs = match input {
+        InputText::String(_) => 1,
+        InputText::Array(inputs) => inputs.len(),
+    };
+    if num_inputs > max_inputs {
+        return Err(format!(
+            "Too many inputs: {}, while at most {} are allowed.",
+            num_inputs, max_inputs
+        ));
+    }
+
+    Ok(())
+}
+
+/// Merge the response of a sub-batch, whose first input is at `offset` in the request, into the response of the previous sub-batches.
+fn merge_embeddings(
+    embedding_response: Option<EmbeddingsResponse>,
+    mut batch_response: EmbeddingsResponse,
+    offset: u64,
+) -> EmbeddingsResponse {
+    // the indexes are relative to the sub-batch
+    for embedding in batch_response.data.iter_mut() {
+        embedding.index += offset;
+    }
+
+    match embedding_response {
+        Some(mut embedding_response) => {
+            embedding_response.data.extend(batch_response.data);
+            embedding_response.usage.prompt_tokens += batch_response.usage.prompt_tokens;
+            embedding_response.usage.completion_tokens += batch_response.usage.completion_tokens;
+            embedding_response.usage.total_tokens += batch_response.usage.total_tokens;
+            embedding_response
+        }
+        None => batch_response,
+    }
+}
+
 /// Query a user input and return a chat-completion response with the answer from the model.
 ///
 /// Note that the body of the request is deserialized to a `ChatCompletionRequest` instance.
@@ -281,24 +970,39 @@ pub(crate) async fn rag_query_handler(
     print_log_begin_separator("RAG (Query user input)", Some("*"), None);
 
     if req.method().eq(&hyper::http::Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
-
-        match result {
-            Ok(response) => return Ok(response),
-            Err(e) => {
-                return error::internal_server_error(e.to_string());
-            }
-        }
+        return preflight_response();
     }
 
+    let version = req.version();
+
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info,
+        None => {
+            return error::internal_server_error("The server info is not set.");
+        }
+    };
+
     // parse request
     let body_bytes = to_bytes(req.body_mut()).await?;
-    let mut chat_request: ChatCompletionRequest = match serde_json::from_slice(&body_bytes) {
+    let body: serde_json::Value = match serde_json::from_slice(&body_bytes) {
+        Ok(body) => body,
+        Err(e) => {
+            return error::bad_request(format!(
+                "Fail to parse chat completion request: {msg}",
+                msg = e
+            ));
+        }
+    };
+    let raw = raw_prompt_requested(&body);
+    let qdrant_config = match effective_qdrant_config(
+        &body,
+        &server_info.qdrant_config,
+        server_info.rag_config.max_qdrant_limit,
+    ) {
+        Ok(qdrant_config) => qdrant_config,
+        Err(e) => return error::bad_request(e),
+    };
+    let chat_request: ChatCompletionRequest = match serde_json::from_value(body) {
         Ok(chat_request) => chat_request,
         Err(e) => {
             return error::bad_request(format!(
@@ -308,9 +1012,367 @@ pub(crate) async fn rag_query_handler(
         }
     };
 
-    if chat_request.user.is_none() {
-        chat_request.user = Some(gen_chat_id())
-    };
+    let res = match raw {
+        true => raw_completion(chat_request).await,
+        false => rag_query(chat_request, qdrant_config, version).await,
+    };
+
+    print_log_end_separator(Some("*"), None);
+
+    res
+}
+
+/// Answer a chat completion request with the context retrieved from the Qdrant server.
+///
+/// `qdrant_config` is the effective Qdrant config of the request, see [`effective_qdrant_config`].
+async fn rag_query(
+    mut chat_request: ChatCompletionRequest,
+    qdrant_config: QdrantConfig,
+    version: Version,
+) -> Result<Response<Body>, hyper::Error> {
+    record_end_user(&mut chat_request.user);
+
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info,
+        None => {
+            return error::internal_server_error("The server info is not set.");
+        }
+    };
+
+    if let Err(e) = check_model_field(
+        chat_request.model.as_deref(),
+        server_info.rag_config.require_model_field,
+    ) {
+        return error::bad_request(e);
+    }
+
+    if let Err(e) = check_stop_sequences(
+        chat_request.stop.as_deref(),
+        server_info.rag_config.chat_model.reverse_prompt.is_some(),
+        server_info.rag_config.max_stop_sequences,
+    ) {
+        return error::bad_request(e);
+    }
+
+    // the user query is the text of the last message
+    let query_text = match chat_request.messages.last() {
+        Some(ChatCompletionRequestMessage::User(user_message)) => match user_message.content() {
+            ChatCompletionUserMessageContent::Text(text) => text.clone(),
+            _ => return error::bad_request("The last message must be a text content user message"),
+        },
+        Some(_) => return error::bad_request("The last message must be a user message"),
+        None => return error::bad_request("Messages should not be empty"),
+    };
+
+    if chat_request.stream == Some(true) && server_info.rag_config.stream_retrieval_events {
+        return chat_completions_stream_with_events(
+            chat_request,
+            query_text,
+            qdrant_config,
+            version,
+        );
+    }
+
+    let rag_metadata =
+        match retrieve_context(&mut chat_request, &query_text, &qdrant_config, None).await {
+            Ok(rag_metadata) => rag_metadata,
+            Err(e) => return error::internal_server_error(e),
+        };
+
+    // chat completion
+    match chat_request.stream {
+        Some(true) => chat_completions_stream(chat_request, rag_metadata, version).await,
+        Some(false) | None => chat_completions(chat_request, rag_metadata).await,
+    }
+}
+
+/// Log the end-user id sent by the client in the `user` field of a request, so that abuse can be traced back to the end user. If the field is absent, it is set to a generated id, which is logged too. Returns the id.
+fn record_end_user(user: &mut Option<String>) -> String {
+    let generated = user.is_none();
+    let end_user = user.get_or_insert_with(gen_chat_id).clone();
+    println!("\n{}", end_user_log(&end_user, generated));
+
+    end_user
+}
+
+/// The log line of the end-user id of a request.
+fn end_user_log(end_user: &str, generated: bool) -> String {
+    match generated {
+        true => format!("[+] End user: {} (generated)", end_user),
+        false => format!("[+] End user: {}", end_user),
+    }
+}
+
+/// Check that a chat completion request names its model if `--require-model-field` is set. Otherwise, the default chat model is used.
+fn check_model_field(model: Option<&str>, require_model_field: bool) -> Result<(), String> {
+    match model.is_none() && require_model_field {
+        true => Err("The `model` field is required.".to_string()),
+        false => Ok(()),
+    }
+}
+
+/// Check that the stop sequences of a request, plus the reverse prompt of the server if any, do not exceed `max_stop_sequences`.
+fn check_stop_sequences(
+    stop: Option<&[String]>,
+    has_reverse_prompt: bool,
+    max_stop_sequences: usize,
+) -> Result<(), String> {
+    let num_stop_sequences = stop.map_or(0, |stop| stop.len()) + has_reverse_prompt as usize;
+    match num_stop_sequences > max_stop_sequences {
+        true => Err(format!(
+            "Too many stop sequences: {} (including the reverse prompt of the server), while at most {} are allowed.",
+            num_stop_sequences, max_stop_sequences
+        )),
+        false => Ok(()),
+    }
+}
+
+/// Compute the embedding of the user query and search the Qdrant collection with it. Returns `None` if the retrieval is skipped because Qdrant is unavailable and `--qdrant-outage-behavior` is `degrade`.
+async fn embed_and_retrieve(
+    query_text: &str,
+    user: Option<String>,
+    qdrant_config: &QdrantConfig,
+    explanations: Option<&mut Vec<retrieval::ChunkExplanation>>,
+) -> Result<Option<Vec<retrieval::RetrievedPoint>>, String> {
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info,
+        None => return Err("The server info is not set.".to_string()),
+    };
+
+    println!("\n[+] Computing embeddings for user query ...");
+
+    // * compute embeddings for user query
+    println!("    * user query: {}\n", query_text);
+
+    // get the available embedding models
+    let embedding_model_names =
+        llama_core::utils::embedding_model_names().map_err(|e| e.to_string())?;
+
+    // create a embedding request
+    let embedding_request = EmbeddingRequest {
+        model: embedding_model_names[0].clone(),
+        input: query_text.into(),
+        encoding_format: None,
+        user,
+    };
+
+    if let Ok(request_str) = serde_json::to_string_pretty(&embedding_request) {
+        println!("    * embedding request (json):\n\n{}", request_str);
+    }
+
+    let rag_embedding_request = RagEmbeddingRequest {
+        embedding_request,
+        qdrant_url: qdrant_config.url.clone(),
+        qdrant_collection_name: qdrant_config.collection_name.clone(),
+    };
+
+    // compute embeddings for query
+    let embedding_response = llama_core::rag::rag_query_to_embeddings(&rag_embedding_request)
+        .await
+        .map_err(|e| e.to_string())?;
+    let query_embedding: Vec<f32> = match embedding_response.data.first() {
+        Some(embedding) => embedding.embedding.iter().map(|x| *x as f32).collect(),
+        None => return Err("No embeddings returned".to_string()),
+    };
+    retrieval::check_query_embedding(
+        &query_embedding,
+        qdrant_config,
+        Duration::from_secs(server_info.rag_config.qdrant_connect_timeout),
+    )
+    .await?;
+
+    // * retrieve context
+    let degrade = server_info.rag_config.qdrant_outage_behavior == QdrantOutageBehavior::Degrade;
+    if degrade && !retrieval::qdrant_available() {
+        println!("    * Qdrant is unavailable. Skip retrieval.");
+        return Ok(None);
+    }
+    match retrieval::retrieve_points(
+        query_embedding.as_slice(),
+        qdrant_config,
+        &server_info.rag_config,
+        explanations,
+    )
+    .await
+    {
+        Ok(scored_points) => {
+            retrieval::record_qdrant_result(true);
+            Ok(Some(scored_points))
+        }
+        Err(e) => {
+            retrieval::record_qdrant_result(false);
+            match degrade {
+                true => {
+                    println!("    * Failed to retrieve context. Skip retrieval. {}", e);
+                    Ok(None)
+                }
+                false => Err(e),
+            }
+        }
+    }
+}
+
+/// Retrieve the context of the user query from the Qdrant server, and merge it into the messages of the chat request.
+///
+/// If `events` is given, a `retrieval` event is sent to it as each stage starts or ends.
+async fn retrieve_context(
+    chat_request: &mut ChatCompletionRequest,
+    query_text: &str,
+    qdrant_config: &QdrantConfig,
+    mut events: Option<&mut hyper::body::Sender>,
+) -> Result<RagMetadata, String> {
+    let server_info = match SERVER_INFO.get() {
+        Some(server_info) => server_info,
+        None => return Err("The server info is not set.".to_string()),
+    };
+
+    if let Some(events) = events.as_deref_mut() {
+        send_retrieval_event(events, serde_json::json!({ "stage": "embedding" })).await?;
+    }
+
+    println!("\n[+] Retrieving context ...");
+
+    let mut rag_metadata = RagMetadata::default();
+
+    // the explanations are returned in the response body, which is not available in stream mode
+    let mut explanations =
+        match server_info.rag_config.debug_retrieval && chat_request.stream != Some(true) {
+            true => Some(vec![]),
+            false => None,
+        };
+    // cached results carry no explanation, so the cache is not looked up while explaining
+    let use_cached = explanations.is_none();
+    let user = chat_request.user.clone();
+    let explanations_mut = explanations.as_mut();
+    let scored_points = retrieval::cached_or_retrieve(
+        query_text,
+        qdrant_config,
+        &server_info.rag_config,
+        use_cached,
+        || embed_and_retrieve(query_text, user, qdrant_config, explanations_mut),
+    )
+    .await?;
+    let scored_points = match scored_points {
+        Some(scored_points) => scored_points,
+        None => {
+            rag_metadata.retrieval_unavailable = true;
+            vec![]
+        }
+    };
+
+    if let Some(explanations) = explanations {
+        println!(
+            "    * Explained {} candidate point(s), {} dropped",
+            explanations.len(),
+            explanations
+                .iter()
+                .filter(|explanation| explanation.dropped_by.is_some())
+                .count()
+        );
+        rag_metadata.debug = Some(RagDebug {
+            retrieval_explanation: explanations,
+        });
+    }
+
+    if let Some(aggregation) = server_info.rag_config.grounding_score {
+        let scores: Vec<f32> = scored_points.iter().map(|point| point.score).collect();
+        let grounding_score = aggregation.aggregate(&scores);
+        println!(
+            "    * Grounding score ({}): {}",
+            aggregation, grounding_score
+        );
+        rag_metadata.grounding_score = Some(grounding_score);
+    }
+
+    if let Some(events) = events {
+        // the response headers are already sent, so the metadata goes to the event
+        let mut data = serde_json::json!({ "stage": "search", "chunks": scored_points.len() });
+        if let Some(grounding_score) = rag_metadata.grounding_score {
+            data["x_grounding_score"] = grounding_score.into();
+        }
+        if rag_metadata.retrieval_unavailable {
+            data["retrieval_unavailable"] = true.into();
+        }
+        send_retrieval_event(events, data).await?;
+    }
+
+    match scored_points.is_empty() {
+        true => {
+            if !rag_metadata.retrieval_unavailable {
+                let min_chunk_chars = server_info.rag_config.min_chunk_chars;
+                match min_chunk_chars {
+                    0 => println!(
+                        "    * No point retrieved (score < threshold {})",
+                        qdrant_config.score_threshold
+                    ),
+                    _ => println!(
+                        "    * No point retrieved (score < threshold {}, or chars < {})",
+                        qdrant_config.score_threshold, min_chunk_chars
+                    ),
+                }
+            }
+            println!("\n[+] Answer the user query ...");
+        }
+        false => {
+            // update messages with retrieved context
+            let mut context = String::new();
+            for (idx, point) in scored_points.iter().enumerate() {
+                println!(
+                    "{}",
+                    point_log(idx, point, server_info.rag_config.log_chunk_ids_only)
+                );
+
+                context.push_str(&point.source);
+                context.push_str("\n\n");
+            }
+
+            if chat_request.messages.is_empty() {
+                return Err("No message in the chat request.".to_string());
+            }
+
+            let prompt_template =
+                llama_core::utils::chat_prompt_template(chat_request.model.as_deref())
+                    .map_err(|e| e.to_string())?;
+
+            // insert rag context into chat request
+            RagPromptBuilder::build(
+                &mut chat_request.messages,
+                &[context],
+                prompt_template.has_system_prompt(),
+                server_info.rag_config.policy,
+            )
+            .map_err(|e| e.to_string())?;
+
+            println!("\n[+] Answer the user query with the context info ...");
+        }
+    }
+
+    Ok(rag_metadata)
+}
+
+/// Format the log of a retrieved point: its id and score, followed by the text of the chunk unless `ids_only` is set.
+fn point_log(idx: usize, point: &retrieval::RetrievedPoint, ids_only: bool) -> String {
+    let mut log = format!(
+        "    * Point {}: id: {}, score: {}",
+        idx, point.id, point.score
+    );
+    if !ids_only {
+        log.push_str(&format!("\n      Source: {}", &point.source));
+    }
+    log
+}
+
+/// Answer a batch of chat completion requests one by one, and return the results in the order of the requests.
+///
+/// A request that fails does not fail the whole batch; its result is an error object instead.
+pub(crate) async fn chat_batch_handler(
+    mut req: Request<Body>,
+) -> Result<Response<Body>, hyper::Error> {
+    print_log_begin_separator("RAG (Batch of chat completions)", Some("*"), None);
+
+    if req.method().eq(&hyper::http::Method::OPTIONS) {
+        return preflight_response();
+    }
 
     let server_info = match SERVER_INFO.get() {
         Some(server_info) => server_info,
@@ -319,151 +1381,240 @@ pub(crate) async fn rag_query_handler(
         }
     };
 
-    println!("\n[+] Computing embeddings for user query ...");
-
-    // * compute embeddings for user query
-    let embedding_response = match chat_request.messages.is_empty() {
-        true => return error::bad_request("Messages should not be empty"),
-        false => {
-            let last_message = chat_request.messages.last().unwrap();
-            match last_message {
-                ChatCompletionRequestMessage::User(user_message) => {
-                    let query_text = match user_message.content() {
-                        ChatCompletionUserMessageContent::Text(text) => text,
-                        _ => {
-                            return error::bad_request(
-                                "The last message must be a text content user message",
-                            )
-                        }
-                    };
-
-                    println!("    * user query: {}\n", query_text);
-
-                    // get the available embedding models
-                    let embedding_model_names = match llama_core::utils::embedding_model_names() {
-                        Ok(model_names) => model_names,
-                        Err(e) => return error::internal_server_error(e.to_string()),
-                    };
-
-                    // create a embedding request
-                    let embedding_request = EmbeddingRequest {
-                        model: embedding_model_names[0].clone(),
-                        input: query_text.into(),
-                        encoding_format: None,
-                        user: chat_request.user.clone(),
-                    };
-
-                    if let Ok(request_str) = serde_json::to_string_pretty(&embedding_request) {
-                        println!("    * embedding request (json):\n\n{}", request_str);
-                    }
+    // parse request. The items are parsed one by one, so that an invalid item does not fail the whole batch.
+    let version = req.version();
+    let body_bytes = to_bytes(req.body_mut()).await?;
+    let items: Vec<serde_json::Value> = match serde_json::from_slice(&body_bytes) {
+        Ok(items) => items,
+        Err(e) => {
+            return error::bad_request(format!(
+                "Fail to parse the batch of chat completion requests: {msg}",
+                msg = e
+            ));
+        }
+    };
 
-                    let rag_embedding_request = RagEmbeddingRequest {
-                        embedding_request,
-                        qdrant_url: server_info.qdrant_config.url.clone(),
-                        qdrant_collection_name: server_info.qdrant_config.collection_name.clone(),
-                    };
+    if items.len() > server_info.rag_config.max_batch_requests {
+        return error::bad_request(format!(
+            "Too many requests in the batch: {}, while at most {} are allowed.",
+            items.len(),
+            server_info.rag_config.max_batch_requests
+        ));
+    }
 
-                    // compute embeddings for query
-                    match llama_core::rag::rag_query_to_embeddings(&rag_embedding_request).await {
-                        Ok(embedding_response) => embedding_response,
-                        Err(e) => {
-                            return error::internal_server_error(e.to_string());
-                        }
-                    }
+    let mut results = Vec::with_capacity(items.len());
+    for (idx, item) in items.into_iter().enumerate() {
+        println!("\n[+] Processing request {} of the batch ...", idx);
+
+        let result = match parse_batch_item(
+            item,
+            &server_info.qdrant_config,
+            server_info.rag_config.max_qdrant_limit,
+        ) {
+            Ok((chat_request, qdrant_config, raw)) => {
+                let response = match raw {
+                    true => raw_completion(chat_request).await?,
+                    false => rag_query(chat_request, qdrant_config, version).await?,
+                };
+                let status = response.status();
+                let body_bytes = to_bytes(response.into_body()).await?;
+                match status.is_success() {
+                    true => match serde_json::from_slice(&body_bytes) {
+                        Ok(object) => object,
+                        Err(e) => batch_error(
+                            hyper::StatusCode::INTERNAL_SERVER_ERROR,
+                            format!("Fail to parse chat completion object. {}", e),
+                        ),
+                    },
+                    false => batch_error(status, String::from_utf8_lossy(&body_bytes)),
                 }
-                _ => return error::bad_request("The last message must be a user message"),
             }
-        }
-    };
-    let query_embedding: Vec<f32> = match embedding_response.data.first() {
-        Some(embedding) => embedding.embedding.iter().map(|x| *x as f32).collect(),
-        None => return error::internal_server_error("No embeddings returned"),
-    };
+            Err(error) => error,
+        };
 
-    println!("\n[+] Retrieving context ...");
+        results.push(result);
+    }
 
-    // * retrieve context
-    let ro = match llama_core::rag::rag_retrieve_context(
-        query_embedding.as_slice(),
-        server_info.qdrant_config.url.to_string().as_str(),
-        server_info.qdrant_config.collection_name.as_str(),
-        server_info.qdrant_config.limit as usize,
-        Some(server_info.qdrant_config.score_threshold),
-    )
-    .await
-    {
-        Ok(search_result) => search_result,
+    print_log_end_separator(Some("*"), None);
+
+    // serialize results
+    let s = match serde_json::to_string(&results) {
+        Ok(s) => s,
         Err(e) => {
-            return error::internal_server_error(e.to_string());
+            return error::internal_server_error(format!(
+                "Fail to serialize the batch results. {}",
+                e
+            ));
         }
     };
 
-    match ro.points {
-        Some(scored_points) => {
-            match scored_points.is_empty() {
-                true => {
-                    println!(
-                        "    * No point retrieved (score < threshold {})",
-                        server_info.qdrant_config.score_threshold
-                    );
-                    println!("\n[+] Answer the user query ...");
-                }
-                false => {
-                    // update messages with retrieved context
-                    let mut context = String::new();
-                    for (idx, point) in scored_points.iter().enumerate() {
-                        println!("    * Point {}: score: {}", idx, point.score);
-                        println!("      Source: {}", &point.source);
-
-                        context.push_str(&point.source);
-                        context.push_str("\n\n");
-                    }
+    // return response
+    let result = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .body(Body::from(s));
 
-                    if chat_request.messages.is_empty() {
-                        return error::internal_server_error("No message in the chat request.");
-                    }
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => error::internal_server_error(e.to_string()),
+    }
+}
 
-                    let prompt_template = match llama_core::utils::chat_prompt_template(
-                        chat_request.model.as_deref(),
-                    ) {
-                        Ok(prompt_template) => prompt_template,
-                        Err(e) => {
-                            return error::internal_server_error(e.to_string());
-                        }
-                    };
+/// Parse a request of a batch into the chat completion request, its effective Qdrant config, and whether a raw prompt is requested. An invalid request is turned into its error object.
+fn parse_batch_item(
+    item: serde_json::Value,
+    qdrant_config: &QdrantConfig,
+    max_qdrant_limit: u64,
+) -> Result<(ChatCompletionRequest, QdrantConfig, bool), serde_json::Value> {
+    let qdrant_config = effective_qdrant_config(&item, qdrant_config, max_qdrant_limit)
+        .map_err(|e| batch_error(hyper::StatusCode::BAD_REQUEST, e))?;
+    let raw = raw_prompt_requested(&item);
+    let chat_request = serde_json::from_value::<ChatCompletionRequest>(item).map_err(|e| {
+        batch_error(
+            hyper::StatusCode::BAD_REQUEST,
+            format!("Fail to parse chat completion request: {msg}", msg = e),
+        )
+    })?;
+    if chat_request.stream == Some(true) {
+        return Err(batch_error(
+            hyper::StatusCode::BAD_REQUEST,
+            "Stream mode is not supported in batch requests.",
+        ));
+    }
 
-                    // insert rag context into chat request
-                    if let Err(e) = RagPromptBuilder::build(
-                        &mut chat_request.messages,
-                        &[context],
-                        prompt_template.has_system_prompt(),
-                        server_info.rag_config.policy,
-                    ) {
-                        return error::internal_server_error(e.to_string());
-                    }
+    Ok((chat_request, qdrant_config, raw))
+}
 
-                    println!("\n[+] Answer the user query with the context info ...");
-                }
-            }
+/// Create the error object standing for a failed request in a batch.
+fn batch_error(status: hyper::StatusCode, msg: impl AsRef<str>) -> serde_json::Value {
+    serde_json::json!({
+        "error": {
+            "code": status.as_u16(),
+            "message": msg.as_ref(),
         }
-        None => {
-            println!(
-                "    * No point retrieved (score < threshold {})",
-                server_info.qdrant_config.score_threshold
+    })
+}
+
+/// Whether the `raw_prompt` field of a chat completion request is `true`.
+fn raw_prompt_requested(body: &serde_json::Value) -> bool {
+    body.get("raw_prompt")
+        .and_then(|raw_prompt| raw_prompt.as_bool())
+        .unwrap_or(false)
+}
+
+/// Optional fields of a chat completion request overriding the Qdrant config of the server.
+#[derive(Debug, Default, Deserialize)]
+struct QdrantOverrides {
+    /// Max number of retrieved results
+    qdrant_limit: Option<u64>,
+    /// Minimal score threshold for the search results
+    qdrant_score_threshold: Option<f32>,
+    /// Name of the collection to search
+    qdrant_collection_name: Option<String>,
+}
+
+/// Build the Qdrant config of a chat completion request from the `qdrant_limit`, `qdrant_score_threshold` and `qdrant_collection_name` fields of the request body, falling back to the Qdrant config of the server for absent fields.
+///
+/// `qdrant_limit` is capped by `max_qdrant_limit`, the `--max-qdrant-limit` CLI option.
+fn effective_qdrant_config(
+    body: &serde_json::Value,
+    qdrant_config: &QdrantConfig,
+    max_qdrant_limit: u64,
+) -> Result<QdrantConfig, String> {
+    let overrides = QdrantOverrides::deserialize(body)
+        .map_err(|e| format!("Invalid Qdrant override: {}", e))?;
+
+    if let Some(limit) = overrides.qdrant_limit {
+        if limit == 0 {
+            return Err(
+                "Invalid Qdrant override: `qdrant_limit` must be greater than 0.".to_string(),
             );
-            println!("\n[+] Answer the user query ...");
         }
+        if limit > max_qdrant_limit {
+            return Err(format!(
+                "Invalid Qdrant override: `qdrant_limit` must not exceed {}, but got {}.",
+                max_qdrant_limit, limit
+            ));
+        }
+    }
+    if let Some(score_threshold) = overrides.qdrant_score_threshold {
+        if !(0.0..=1.0).contains(&score_threshold) {
+            return Err(format!(
+                "Invalid Qdrant override: `qdrant_score_threshold` must be in the range [0.0, 1.0], but got {}.",
+                score_threshold
+            ));
+        }
+    }
+    if overrides.qdrant_collection_name.as_deref() == Some("") {
+        return Err(
+            "Invalid Qdrant override: `qdrant_collection_name` must not be empty.".to_string(),
+        );
     }
 
-    // chat completion
-    let res = match chat_request.stream {
-        Some(true) => chat_completions_stream(chat_request).await,
-        Some(false) | None => chat_completions(chat_request).await,
-    };
+    Ok(QdrantConfig {
+        url: qdrant_config.url.clone(),
+        collection_name: overrides
+            .qdrant_collection_name
+            .unwrap_or_else(|| qdrant_config.collection_name.clone()),
+        limit: overrides.qdrant_limit.unwrap_or(qdrant_config.limit),
+        score_threshold: overrides
+            .qdrant_score_threshold
+            .unwrap_or(qdrant_config.score_threshold),
+    })
+}
 
-    print_log_end_separator(Some("*"), None);
+/// Extra fields attached to the chat completion response by the RAG pipeline.
+#[derive(Debug, Default, Serialize)]
+struct RagMetadata {
+    /// Aggregated score of the chunks injected into the prompt
+    #[serde(rename = "x_grounding_score", skip_serializing_if = "Option::is_none")]
+    grounding_score: Option<f32>,
+    /// Whether the answer is generated without retrieval because Qdrant is unavailable
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    retrieval_unavailable: bool,
+    /// Whether the response is truncated to fit the `--max-response-bytes` limit
+    #[serde(
+        rename = "x_response_truncated",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    response_truncated: bool,
+    /// Debug information, returned if the server is started with `--debug-retrieval`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<RagDebug>,
+}
 
-    res
+/// Debug information attached to the chat completion response.
+#[derive(Debug, Serialize)]
+struct RagDebug {
+    /// Explanation of each candidate chunk of the retrieval, in the order of the scores
+    retrieval_explanation: Vec<retrieval::ChunkExplanation>,
+}
+
+/// Format a retrieval progress event. Clients that do not know the `retrieval` event type ignore it.
+fn retrieval_event(data: serde_json::Value) -> String {
+    format!("event: retrieval\ndata: {}\n\n", data)
+}
+
+/// Send a retrieval progress event to the body of a stream.
+async fn send_retrieval_event(
+    sender: &mut hyper::body::Sender,
+    data: serde_json::Value,
+) -> Result<(), String> {
+    sender
+        .send_data(retrieval_event(data).into())
+        .await
+        .map_err(|e| format!("Failed to send the retrieval event. {}", e))
+}
+
+/// Chat completion object extended with the RAG metadata.
+#[derive(Debug, Serialize)]
+struct RagChatCompletionObject {
+    #[serde(flatten)]
+    object: ChatCompletionObject,
+    #[serde(flatten)]
+    metadata: RagMetadata,
 }
 
 #[derive(Debug, Default)]
@@ -1048,6 +2199,27 @@ pub(crate) async fn doc_to_embeddings(
                 Err(e) => return error::internal_server_error(e.to_string()),
             };
 
+        // the collection may be recreated with a different dimension
+        retrieval::forget_collection_dimension(&server_info.qdrant_config);
+        // the cached results may miss the new chunks
+        retrieval::invalidate_retrieval_cache(&server_info.qdrant_config);
+
+        if server_info.rag_config.detect_chunk_language {
+            println!("\n[+] Detecting the language of chunks ...");
+            if let InputText::Array(chunks) = &rag_embedding_request.embedding_request.input {
+                if let Err(e) = retrieval::store_chunk_languages(
+                    &server_info.qdrant_config,
+                    chunks,
+                    &embedding_response.data,
+                )
+                .await
+                {
+                    // the embeddings are already stored, so only the `lang` fields are missing
+                    println!("    * Failed to store the language of chunks. {}", e);
+                }
+            }
+        }
+
         print_log_begin_separator("RAG (Embeddings for chunks)", Some("*"), None);
 
         embedding_response
@@ -1108,19 +2280,7 @@ pub(crate) async fn retrieve_handler(
     mut req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
     if req.method().eq(&hyper::http::Method::OPTIONS) {
-        let result = Response::builder()
-            .header("Access-Control-Allow-Origin", "*")
-            .header("Access-Control-Allow-Methods", "*")
-            .header("Access-Control-Allow-Headers", "*")
-            .header("Content-Type", "application/json")
-            .body(Body::empty());
-
-        match result {
-            Ok(response) => return Ok(response),
-            Err(e) => {
-                return error::internal_server_error(e.to_string());
-            }
-        }
+        return preflight_response();
     }
 
     // parse request
@@ -1207,20 +2367,39 @@ pub(crate) async fn retrieve_handler(
         Some(embedding) => embedding.embedding.iter().map(|x| *x as f32).collect(),
         None => return error::internal_server_error("No embeddings returned"),
     };
+    if let Err(e) = retrieval::check_query_embedding(
+        &query_embedding,
+        &server_info.qdrant_config,
+        Duration::from_secs(server_info.rag_config.qdrant_connect_timeout),
+    )
+    .await
+    {
+        return error::internal_server_error(e);
+    }
 
     println!("\n[+] Retrieving context ...");
 
     // * retrieve context
-    match llama_core::rag::rag_retrieve_context(
-        query_embedding.as_slice(),
-        server_info.qdrant_config.url.to_string().as_str(),
-        server_info.qdrant_config.collection_name.as_str(),
-        server_info.qdrant_config.limit as usize,
+    match retrieval::search_points(
+        &query_embedding,
+        &server_info.qdrant_config,
         Some(server_info.qdrant_config.score_threshold),
     )
     .await
     {
-        Ok(retrieve_object) => {
+        Ok(points) => {
+            let points: Vec<RagScoredPoint> = points
+                .into_iter()
+                .map(|point| RagScoredPoint {
+                    source: point.source,
+                    score: point.score,
+                })
+                .collect();
+            let retrieve_object = RetrieveObject {
+                points: (!points.is_empty()).then_some(points),
+                limit: server_info.qdrant_config.limit as usize,
+                score_threshold: server_info.qdrant_config.score_threshold,
+            };
             if let Some(points) = &retrieve_object.points {
                 println!("    * {} point(s) retrieved", points.len())
             }
@@ -1250,6 +2429,573 @@ pub(crate) async fn retrieve_handler(
                 Err(e) => error::internal_server_error(e.to_string()),
             }
         }
-        Err(e) => error::internal_server_error(e.to_string()),
+        Err(e) => error::internal_server_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retrieval::tests::qdrant_config;
+
+    fn point(id: &str, source: &str, score: f32) -> retrieval::RetrievedPoint {
+        retrieval::RetrievedPoint {
+            id: id.to_string(),
+            source: serde_json::to_string(source).unwrap(),
+            score,
+            vector: None,
+        }
+    }
+
+    fn content_chunk(content: &str) -> String {
+        let chunk = serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "default",
+            "system_fingerprint": "fp",
+            "choices": [{
+                "index": 0,
+                "delta": { "role": "assistant", "content": content },
+                "logprobs": null,
+                "finish_reason": null,
+            }],
+        });
+        format!("data: {}\n\n", chunk)
+    }
+
+    #[tokio::test]
+    async fn test_retrieval_events_precede_content() {
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            send_retrieval_event(&mut sender, serde_json::json!({ "stage": "embedding" }))
+                .await
+                .unwrap();
+            send_retrieval_event(
+                &mut sender,
+                serde_json::json!({ "stage": "search", "chunks": 2 }),
+            )
+            .await
+            .unwrap();
+            let stream = futures_util::stream::iter(vec![
+                Ok(content_chunk("Hello")),
+                Ok("data: [DONE]\n\n".to_string()),
+            ]);
+            send_stream(sender, stream, None, false).await;
+        });
+
+        let body = to_bytes(body).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let embedding = body
+            .find("event: retrieval\ndata: {\"stage\":\"embedding\"}\n\n")
+            .unwrap();
+        let search = body
+            .find("event: retrieval\ndata: {\"chunks\":2,\"stage\":\"search\"}\n\n")
+            .unwrap();
+        let content = body.find("Hello").unwrap();
+        assert!(embedding < search);
+        assert!(search < content);
+    }
+
+    #[test]
+    fn test_prepare_stream_usage() {
+        // over HTTP/2, the usage chunk is requested for the trailers, but not forwarded
+        let mut chat_request = ChatCompletionRequest::default();
+        assert_eq!(
+            prepare_stream_usage(&mut chat_request, Version::HTTP_2),
+            (false, true)
+        );
+        assert!(chat_request
+            .stream_options
+            .and_then(|stream_options| stream_options.include_usage)
+            .unwrap_or(false));
+
+        // over HTTP/1.1, the stream options are left as is
+        let mut chat_request = ChatCompletionRequest::default();
+        assert_eq!(
+            prepare_stream_usage(&mut chat_request, Version::HTTP_11),
+            (false, false)
+        );
+        assert!(chat_request.stream_options.is_none());
+
+        let mut chat_request = ChatCompletionRequest {
+            stream_options: Some(StreamOptions {
+                include_usage: Some(true),
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            prepare_stream_usage(&mut chat_request, Version::HTTP_2),
+            (true, true)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_usage_trailers() {
+        use hyper::body::HttpBody;
+
+        let usage_chunk = serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "default",
+            "system_fingerprint": "fp",
+            "choices": [],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 2, "total_tokens": 7 },
+        });
+        let usage_chunk = format!("data: {}\n\n", usage_chunk);
+
+        for include_usage in [false, true] {
+            let (sender, mut body) = Body::channel();
+            let stream = futures_util::stream::iter(vec![
+                Ok(content_chunk("Hello")),
+                Ok(usage_chunk.clone()),
+                Ok("data: [DONE]\n\n".to_string()),
+            ]);
+            tokio::spawn(send_stream(
+                sender,
+                stream,
+                Some(Instant::now()),
+                include_usage,
+            ));
+
+            let mut data = Vec::new();
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+            let data = String::from_utf8(data).unwrap();
+            assert_eq!(data.contains("\"usage\""), include_usage);
+            assert!(data.ends_with("data: [DONE]\n\n"));
+
+            // the usage is sent as trailers either way
+            let trailers = body.trailers().await.unwrap().unwrap();
+            assert_eq!(trailers["x-usage-prompt-tokens"], "5");
+            assert_eq!(trailers["x-usage-completion-tokens"], "2");
+            assert_eq!(trailers["x-usage-total-tokens"], "7");
+            assert!(trailers.contains_key("x-generation-time-ms"));
+        }
+    }
+
+    #[test]
+    fn test_record_end_user() {
+        // the id of the end user is kept as the request id
+        let mut user = Some("user-42".to_string());
+        assert_eq!(record_end_user(&mut user), "user-42");
+        assert_eq!(user.as_deref(), Some("user-42"));
+
+        // an anonymous request gets a generated id
+        let mut user = None;
+        let id = record_end_user(&mut user);
+        assert!(id.starts_with("chatcmpl-"));
+        assert_eq!(user, Some(id));
+    }
+
+    #[test]
+    fn test_end_user_log() {
+        assert_eq!(end_user_log("user-42", false), "[+] End user: user-42");
+
+        let line = end_user_log("chatcmpl-1234", true);
+        assert!(line.contains("chatcmpl-1234"));
+        assert!(line.ends_with("(generated)"));
+    }
+
+    #[test]
+    fn test_merge_embeddings() {
+        let batch_response = |num_inputs: u64, prompt_tokens: u64| EmbeddingsResponse {
+            object: "list".to_string(),
+            data: (0..num_inputs)
+                .map(|index| endpoints::embeddings::EmbeddingObject {
+                    index,
+                    object: "embedding".to_string(),
+                    embedding: vec![index as f64],
+                })
+                .collect(),
+            model: "embedding".to_string(),
+            usage: Usage {
+                prompt_tokens,
+                completion_tokens: 0,
+                total_tokens: prompt_tokens,
+            },
+        };
+
+        // sub-batches of 2 inputs for 5 inputs
+        let mut embedding_response = None;
+        for (offset, num_inputs) in [(0, 2), (2, 2), (4, 1)] {
+            embedding_response = Some(merge_embeddings(
+                embedding_response,
+                batch_response(num_inputs, 10 * num_inputs),
+                offset,
+            ));
+        }
+        let embedding_response = embedding_response.unwrap();
+
+        let indexes: Vec<u64> = embedding_response
+            .data
+            .iter()
+            .map(|embedding| embedding.index)
+            .collect();
+        assert_eq!(indexes, [0, 1, 2, 3, 4]);
+        // the embeddings keep their order within the sub-batches
+        assert_eq!(embedding_response.data[3].embedding, [1.0]);
+        assert_eq!(embedding_response.usage.prompt_tokens, 50);
+        assert_eq!(embedding_response.usage.total_tokens, 50);
+        assert_eq!(embedding_response.model, "embedding");
+    }
+
+    #[test]
+    fn test_check_embedding_inputs() {
+        let inputs = |n: usize| InputText::Array((0..n).map(|i| format!("input {i}")).collect());
+
+        assert!(check_embedding_inputs(&inputs(3), 3).is_ok());
+        assert_eq!(
+            check_embedding_inputs(&inputs(4), 3).unwrap_err(),
+            "Too many inputs: 4, while at most 3 are allowed."
+        );
+
+        // a single string counts as one input
+        assert!(check_embedding_inputs(&InputText::String("a, b, c".to_string()), 1).is_ok());
+        assert!(check_embedding_inputs(&InputText::String("a".to_string()), 0).is_err());
+    }
+
+    #[test]
+    fn test_raw_prompt_requests() {
+        let body = |raw_prompt: serde_json::Value| {
+            serde_json::json!({
+                "messages": [{ "role": "user", "content": "<s>[INST] Hi [/INST]" }],
+                "raw_prompt": raw_prompt,
+            })
+        };
+        assert!(raw_prompt_requested(&body(true.into())));
+        assert!(!raw_prompt_requested(&body(false.into())));
+        assert!(!raw_prompt_requested(&body("true".into())));
+        assert!(!raw_prompt_requested(
+            &serde_json::json!({ "messages": [] })
+        ));
+
+        // the sampling fields are rejected, since they cannot be applied
+        let chat_request: ChatCompletionRequest =
+            serde_json::from_value(body(true.into())).unwrap();
+        assert!(check_raw_prompt_fields(&chat_request).is_ok());
+        let mut sampled = body(true.into());
+        sampled["temperature"] = 0.2.into();
+        sampled["stop"] = serde_json::json!(["</s>"]);
+        let chat_request: ChatCompletionRequest = serde_json::from_value(sampled).unwrap();
+        assert_eq!(
+            check_raw_prompt_fields(&chat_request).unwrap_err(),
+            "Raw prompts do not support the `temperature`, `stop` field(s)."
+        );
+    }
+
+    #[test]
+    fn test_raw_completion_request() {
+        let prompt = "  <s>[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nWhat is RAG? [/INST]\n";
+        let chat_request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+            "model": "default",
+            "messages": [
+                { "role": "system", "content": "You are a helpful assistant." },
+                { "role": "user", "content": prompt },
+            ],
+            "raw_prompt": true,
+        }))
+        .unwrap();
+
+        // the prompt is the user message byte for byte: no template markers are added, and nothing is trimmed
+        let completion_request = raw_completion_request(&chat_request).unwrap();
+        assert_eq!(completion_request.prompt, [prompt.to_string()]);
+        assert_eq!(completion_request.model.as_deref(), Some("default"));
+
+        let chat_request: ChatCompletionRequest = serde_json::from_value(serde_json::json!({
+            "messages": [{ "role": "assistant", "content": "Hello" }],
+        }))
+        .unwrap();
+        assert_eq!(
+            raw_completion_request(&chat_request).unwrap_err(),
+            "The last message must be a user message"
+        );
+    }
+
+    #[test]
+    fn test_raw_chat_completion_object() {
+        let completion_object: CompletionObject = serde_json::from_value(serde_json::json!({
+            "id": "cmpl-1",
+            "object": "text_completion",
+            "created": 0,
+            "model": "default",
+            "choices": [{
+                "index": 0,
+                "text": "  Paris is the capital of France.\n",
+                "finish_reason": "stop",
+                "logprobs": null,
+            }],
+            "usage": { "prompt_tokens": 12, "completion_tokens": 8, "total_tokens": 20 },
+        }))
+        .unwrap();
+
+        let object = raw_chat_completion_object(completion_object);
+        assert_eq!(object.object, "chat.completion");
+        assert_eq!(object.id, "cmpl-1");
+        // the generated text is kept verbatim, including its whitespaces
+        let choice = &object.choices[0];
+        assert_eq!(
+            choice.message.content,
+            "  Paris is the capital of France.\n"
+        );
+        assert_eq!(choice.message.role, ChatCompletionRole::Assistant);
+        assert!(matches!(choice.finish_reason, FinishReason::stop));
+        assert_eq!(object.usage.total_tokens, 20);
+    }
+
+    #[test]
+    fn test_require_model_field() {
+        // chat completion requests fall back to the default model unless the field is required
+        assert!(check_model_field(None, false).is_ok());
+        assert_eq!(
+            check_model_field(None, true).unwrap_err(),
+            "The `model` field is required."
+        );
+        assert!(check_model_field(Some("default"), true).is_ok());
+
+        // embedding requests always require the field, with an explicit message if required
+        let body = br#"{"input":"Hello"}"#;
+        assert_eq!(
+            parse_embedding_request(body, true).unwrap_err(),
+            "The `model` field is required."
+        );
+        let e = parse_embedding_request(body, false).unwrap_err();
+        assert!(e.contains("missing field `model`"));
+
+        let body = br#"{"model":"embedding","input":"Hello"}"#;
+        assert!(parse_embedding_request(body, true).is_ok());
+        assert!(parse_embedding_request(body, false).is_ok());
+    }
+
+    #[test]
+    fn test_parse_batch_items() {
+        let items: Vec<serde_json::Value> = serde_json::from_str(
+            r#"[
+                {"messages":[{"role":"user","content":"Who is Robert Oppenheimer?"}]},
+                {"messages":"not a list of messages"},
+                {"messages":[{"role":"user","content":"What is RAG?"}],"qdrant_limit":2}
+            ]"#,
+        )
+        .unwrap();
+
+        let results: Vec<_> = items
+            .into_iter()
+            .map(|item| parse_batch_item(item, &qdrant_config(), 100))
+            .collect();
+        assert!(results[0].is_ok());
+        let error = results[1].as_ref().unwrap_err();
+        assert_eq!(error["error"]["code"], 400);
+        assert!(error["error"]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("Fail to parse chat completion request"));
+        let (_, qdrant_config, raw) = results[2].as_ref().unwrap();
+        assert_eq!(qdrant_config.limit, 2);
+        assert!(!raw);
+    }
+
+    #[test]
+    fn test_effective_qdrant_config() {
+        let config = |body: serde_json::Value| effective_qdrant_config(&body, &qdrant_config(), 10);
+
+        // absent fields fall back to the server config
+        let fallback = config(serde_json::json!({})).unwrap();
+        assert_eq!(fallback.collection_name, "default");
+        assert_eq!(fallback.limit, 5);
+        assert_eq!(fallback.score_threshold, 0.4);
+
+        let overridden = config(serde_json::json!({
+            "qdrant_limit": 10,
+            "qdrant_score_threshold": 0.0,
+            "qdrant_collection_name": "papers",
+        }))
+        .unwrap();
+        assert_eq!(overridden.collection_name, "papers");
+        assert_eq!(overridden.limit, 10);
+        assert_eq!(overridden.score_threshold, 0.0);
+
+        assert!(config(serde_json::json!({ "qdrant_limit": 0 })).is_err());
+        let error = config(serde_json::json!({ "qdrant_limit": 11 })).unwrap_err();
+        assert!(error.contains("must not exceed 10"));
+        assert!(config(serde_json::json!({ "qdrant_limit": u64::MAX })).is_err());
+        assert!(config(serde_json::json!({ "qdrant_score_threshold": -0.1 })).is_err());
+        assert!(config(serde_json::json!({ "qdrant_score_threshold": 1.1 })).is_err());
+        assert!(config(serde_json::json!({ "qdrant_collection_name": "" })).is_err());
+        assert!(config(serde_json::json!({ "qdrant_limit": "5" })).is_err());
+    }
+
+    #[test]
+    fn test_parse_batch_item_in_stream_mode() {
+        let item = serde_json::json!({
+            "messages": [{ "role": "user", "content": "What is RAG?" }],
+            "stream": true,
+        });
+        let error = parse_batch_item(item, &qdrant_config(), 100).unwrap_err();
+        assert_eq!(
+            error["error"]["message"],
+            "Stream mode is not supported in batch requests."
+        );
+    }
+
+    #[test]
+    fn test_check_stop_sequences() {
+        let stop: Vec<String> = ["###", "</s>"].iter().map(|s| s.to_string()).collect();
+
+        // at the boundary, with and without the reverse prompt
+        assert!(check_stop_sequences(Some(&stop), true, 3).is_ok());
+        assert!(check_stop_sequences(Some(&stop), false, 2).is_ok());
+        assert!(check_stop_sequences(None, true, 1).is_ok());
+
+        // the reverse prompt counts against the limit
+        let e = check_stop_sequences(Some(&stop), true, 2).unwrap_err();
+        assert!(e.contains("Too many stop sequences: 3"));
+        assert!(check_stop_sequences(None, true, 0).is_err());
+    }
+
+    fn chat_completion_object(content: &str, prompt_tokens: u64) -> ChatCompletionObject {
+        ChatCompletionObject {
+            id: "chatcmpl-1".to_string(),
+            object: "chat.completion".to_string(),
+            created: 0,
+            model: "default".to_string(),
+            choices: vec![ChatCompletionObjectChoice {
+                index: 0,
+                message: ChatCompletionObjectMessage {
+                    role: ChatCompletionRole::Assistant,
+                    content: content.to_string(),
+                    function_call: None,
+                },
+                finish_reason: FinishReason::stop,
+            }],
+            usage: Usage {
+                prompt_tokens,
+                completion_tokens: 8,
+                total_tokens: prompt_tokens + 8,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limit_stream_bytes() {
+        let polled = std::cell::Cell::new(0);
+        let chunk = content_chunk("Hello");
+        let stream = futures_util::stream::iter(vec![
+            Ok(chunk.clone()),
+            Ok(chunk.clone()),
+            Ok(chunk.clone()),
+            Ok("data: [DONE]\n\n".to_string()),
+        ])
+        .inspect(|_| polled.set(polled.get() + 1));
+
+        let chunks: Vec<String> = limit_stream_bytes(stream, chunk.len() + 10)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0], chunk);
+        assert!(chunks[1].contains("\"finish_reason\":\"length\""));
+        assert!(chunks[1].ends_with("data: [DONE]\n\n"));
+        // the inner stream is drained to its end
+        assert_eq!(polled.get(), 4);
+
+        // draining stops at the first error, which is not sent after the cutoff
+        polled.set(0);
+        let stream = futures_util::stream::iter(vec![
+            Ok(chunk.clone()),
+            Ok(chunk.clone()),
+            Err("failed".to_string()),
+            Ok(chunk.clone()),
+        ])
+        .inspect(|_| polled.set(polled.get() + 1));
+        let chunks: Vec<_> = limit_stream_bytes(stream, chunk.len() + 10).collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks.iter().all(|chunk| chunk.is_ok()));
+        assert_eq!(polled.get(), 3);
+    }
+
+    #[test]
+    fn test_serialize_within() {
+        let content = "Retrieval augmented generation. ".repeat(10);
+        let object = || RagChatCompletionObject {
+            object: chat_completion_object(&content, 42),
+            metadata: RagMetadata {
+                grounding_score: Some(0.9),
+                ..Default::default()
+            },
+        };
+
+        // within the limit, the response is unchanged
+        let full = serde_json::to_string(&object()).unwrap();
+        assert_eq!(serialize_within(object(), full.len()).unwrap(), full);
+
+        // the metadata is dropped, and the content is cut short
+        let max_bytes = full.len() - 50;
+        let s = serialize_within(object(), max_bytes).unwrap();
+        assert!(s.len() <= max_bytes);
+        let value: serde_json::Value = serde_json::from_str(&s).unwrap();
+        assert!(value.get("x_grounding_score").is_none());
+        assert_eq!(value["x_response_truncated"], true);
+        assert_eq!(value["choices"][0]["finish_reason"], "length");
+        let truncated = value["choices"][0]["message"]["content"].as_str().unwrap();
+        assert!(truncated.len() < content.len());
+        assert!(content.starts_with(truncated));
+
+        // the content is emptied if the limit cannot be reached
+        let s = serialize_within(object(), 10).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&s).unwrap();
+        assert_eq!(value["choices"][0]["message"]["content"], "");
+        assert_eq!(value["x_response_truncated"], true);
+    }
+
+    #[test]
+    fn test_prompt_tokens_header() {
+        let response = build_chat_completion_response(
+            chat_completion_object("Hello", 42),
+            RagMetadata::default(),
+            "chatcmpl-1".to_string(),
+            true,
+            usize::MAX,
+        )
+        .unwrap();
+        assert_eq!(response.headers()["X-Prompt-Tokens"], "42");
+
+        let response = build_chat_completion_response(
+            chat_completion_object("Hello", 42),
+            RagMetadata::default(),
+            "chatcmpl-1".to_string(),
+            false,
+            usize::MAX,
+        )
+        .unwrap();
+        assert!(!response.headers().contains_key("X-Prompt-Tokens"));
+    }
+
+    #[test]
+    fn test_preflight_response() {
+        let max_age = *CORS_MAX_AGE.get_or_init(|| 600);
+
+        // the preflight of the endpoints is cached by any origin
+        let response = preflight_response().unwrap();
+        assert_eq!(response.headers()["Access-Control-Allow-Origin"], "*");
+        assert_eq!(
+            response.headers()["Access-Control-Max-Age"],
+            max_age.to_string()
+        );
+    }
+
+    #[test]
+    fn test_point_log_ids_only() {
+        let point = point("42", "The secret recipe of the house", 0.87);
+
+        let log = point_log(0, &point, true);
+        assert!(log.contains("id: 42"));
+        assert!(log.contains("score: 0.87"));
+        assert!(!log.contains("secret recipe"));
+
+        let log = point_log(0, &point, false);
+        assert!(log.contains("id: 42"));
+        assert!(log.contains("secret recipe"));
     }
 }
@@ -9,7 +9,8 @@ pub(crate) async fn handle_llama_request(
 ) -> Result<Response<Body>, hyper::Error> {
     match req.uri().path() {
         "/v1/chat/completions" => ggml::rag_query_handler(req).await,
-        "/v1/models" => ggml::models_handler().await,
+        "/v1/chat/batch" => ggml::chat_batch_handler(req).await,
+        "/v1/models" => ggml::models_handler(req).await,
         "/v1/embeddings" => ggml::embeddings_handler(req).await,
         "/v1/files" => ggml::files_handler(req).await,
         "/v1/chunks" => ggml::chunks_handler(req).await,
@@ -48,6 +48,52 @@ pub(crate) fn bad_request(msg: impl AsRef<str>) -> Result<Response<Body>, hyper:
     Ok(response)
 }
 
+pub(crate) fn unauthorized(msg: impl AsRef<str>) -> Result<Response<Body>, hyper::Error> {
+    let err_msg = match msg.as_ref().is_empty() {
+        true => "401 Unauthorized".to_string(),
+        false => format!("401 Unauthorized: {}", msg.as_ref()),
+    };
+    let body = serde_json::json!({
+        "error": {
+            "code": hyper::StatusCode::UNAUTHORIZED.as_u16(),
+            "message": err_msg,
+        }
+    });
+
+    let response = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Content-Type", "application/json")
+        .header("WWW-Authenticate", "Bearer")
+        .status(hyper::StatusCode::UNAUTHORIZED)
+        .body(Body::from(body.to_string()))
+        .unwrap();
+
+    Ok(response)
+}
+
+pub(crate) fn method_not_allowed(
+    method: impl AsRef<str>,
+    allow: &str,
+) -> Result<Response<Body>, hyper::Error> {
+    let err_msg = format!(
+        "405 Method Not Allowed: {} is not supported by the endpoint",
+        method.as_ref()
+    );
+
+    let response = Response::builder()
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "*")
+        .header("Access-Control-Allow-Headers", "*")
+        .header("Allow", allow)
+        .status(hyper::StatusCode::METHOD_NOT_ALLOWED)
+        .body(Body::from(err_msg))
+        .unwrap();
+
+    Ok(response)
+}
+
 pub(crate) fn invalid_endpoint(msg: impl AsRef<str>) -> Result<Response<Body>, hyper::Error> {
     let err_msg = match msg.as_ref().is_empty() {
         true => "404 The requested service endpoint is not found".to_string(),
@@ -76,6 +122,9 @@ pub enum ServerError {
     /// Error returned while parsing CLI options failed
     #[error("{0}")]
     ArgumentError(String),
+    /// Error returned while the Qdrant server cannot be reached at startup
+    #[error("Failed to connect to the Qdrant server: {0}")]
+    QdrantConnection(String),
     #[error("{0}")]
     Operation(String),
 }
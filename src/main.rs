@@ -1,31 +1,47 @@
 mod backend;
 mod error;
+mod retrieval;
 mod utils;
 
 use anyhow::Result;
 use chat_prompts::{MergeRagContextPolicy, PromptTemplateType};
-use clap::Parser;
+use clap::{
+    parser::ValueSource, Arg, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser,
+};
 use error::ServerError;
 use hyper::{
-    header,
+    header::{self, HeaderValue},
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server, StatusCode,
+    Body, Method, Request, Response, Server, StatusCode,
 };
 use llama_core::MetadataBuilder;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, path::PathBuf};
-use utils::{is_valid_url, log};
+use std::{
+    any::TypeId, collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration,
+};
+use tokio::sync::Notify;
+use utils::{constant_time_eq, is_valid_url, log};
 
 type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
 
 // global system prompt
 pub(crate) static GLOBAL_RAG_PROMPT: OnceCell<String> = OnceCell::new();
+// max age of CORS preflight results in seconds
+pub(crate) static CORS_MAX_AGE: OnceCell<u64> = OnceCell::new();
+// API key required by the `/v1` endpoints
+pub(crate) static API_KEY: OnceCell<String> = OnceCell::new();
 // server info
 pub(crate) static SERVER_INFO: OnceCell<ServerInfo> = OnceCell::new();
 
 // default socket address
 const DEFAULT_SOCKET_ADDRESS: &str = "0.0.0.0:8080";
+// backoff before the first retry to reach the Qdrant server at startup
+const QDRANT_CONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+// maximal backoff between the retries to reach the Qdrant server at startup
+const QDRANT_CONNECT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+// minimal context size of the chat and embedding models
+const MIN_CTX_SIZE: u64 = 256;
 
 #[derive(Clone, Debug)]
 pub struct AppState {
@@ -46,7 +62,7 @@ struct Cli {
         default_value = "default,embedding"
     )]
     model_alias: Vec<String>,
-    /// Sets context sizes for chat and embedding models, respectively. The sizes are separated by comma without space, for example, '--ctx-size 4096,384'. The first value is for the chat model, and the second is for the embedding model.
+    /// Sets context sizes for chat and embedding models, respectively. The sizes are separated by comma without space, for example, '--ctx-size 4096,384'. The first value is for the chat model, and the second is for the embedding model. Each size should be at least 256.
     #[arg(
         short = 'c',
         long,
@@ -61,15 +77,45 @@ struct Cli {
     /// Halt generation at PROMPT, return control.
     #[arg(short, long)]
     reverse_prompt: Option<String>,
+    /// Reject chat completion requests without the `model` field, instead of using the default model. Embedding requests always require the field; the option makes the error explicit
+    #[arg(long)]
+    require_model_field: bool,
+    /// Maximum number of chat completion requests in a batch sent to the `/v1/chat/batch` endpoint
+    #[arg(long, default_value = "8", value_parser = clap::value_parser!(usize))]
+    max_batch_requests: usize,
+    /// Maximum number of stop conditions of a chat completion request, counting the stop sequences of the request and the reverse prompt
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(usize))]
+    max_stop_sequences: usize,
+    /// Maximum number of inputs of a request to the `/v1/embeddings` endpoint
+    #[arg(long, default_value = "2048", value_parser = clap::value_parser!(usize))]
+    max_embedding_inputs: usize,
+    /// Number of inputs of a request to the `/v1/embeddings` endpoint computed at once. Larger requests are computed in sub-batches of this size
+    #[arg(long, default_value = "32", value_parser = clap::value_parser!(usize))]
+    embedding_batch_size: usize,
     /// Sets batch sizes for chat and embedding models, respectively. The sizes are separated by comma without space, for example, '--batch-size 128,64'. The first value is for the chat model, and the second is for the embedding model.
     #[arg(short, long, value_delimiter = ',', default_value = "512,512", value_parser = clap::value_parser!(u64))]
     batch_size: Vec<u64>,
+    /// Accept chat completion requests with `raw_prompt: true`, whose last user message is sent verbatim to the chat model, bypassing the prompt template and RAG
+    #[arg(long)]
+    allow_raw_prompt: bool,
     /// Custom rag prompt.
     #[arg(long)]
     rag_prompt: Option<String>,
     /// Strategy for merging RAG context into chat messages.
     #[arg(long = "rag-policy", default_value_t, value_enum)]
     policy: MergeRagContextPolicy,
+    /// Send `retrieval` SSE events reporting the retrieval stages before the first chunk in stream mode
+    #[arg(long)]
+    stream_retrieval_events: bool,
+    /// Add the `X-Prompt-Tokens` header, the number of tokens of the assembled prompt, to the chat completion responses in non-stream mode
+    #[arg(long)]
+    timing_headers: bool,
+    /// Return the aggregated score of the injected chunks as `x_grounding_score` in the chat completion response
+    #[arg(long, value_enum)]
+    grounding_score: Option<GroundingScoreAggregation>,
+    /// Maximum size of a chat completion response in bytes. Larger responses are truncated, and no limit applies if not set
+    #[arg(long, value_parser = clap::value_parser!(usize))]
+    max_response_bytes: Option<usize>,
     /// URL of Qdrant REST Service
     #[arg(long, default_value = "http://localhost:6333")]
     qdrant_url: String,
@@ -79,15 +125,51 @@ struct Cli {
     /// Max number of retrieved result (no less than 1)
     #[arg(long, default_value = "5", value_parser = clap::value_parser!(u64))]
     qdrant_limit: u64,
+    /// Maximum of `--qdrant-limit` and of the `qdrant_limit` field of the requests
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u64))]
+    max_qdrant_limit: u64,
     /// Minimal score threshold for the search result
     #[arg(long, default_value = "0.4", value_parser = clap::value_parser!(f32))]
     qdrant_score_threshold: f32,
+    /// Number of retries to reach the Qdrant server at startup, with exponential backoff, before the server fails to start
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(u32))]
+    qdrant_connect_retries: u32,
+    /// Number of seconds to wait for a response of the Qdrant server at startup, in the `/health` endpoint, and when looking up the dimension of the collection
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(u64))]
+    qdrant_connect_timeout: u64,
+    /// Behavior of chat completions while Qdrant is unavailable: `fail` returns an error, `degrade` answers without retrieval
+    #[arg(long, default_value_t, value_enum)]
+    qdrant_outage_behavior: QdrantOutageBehavior,
+    /// Number of seconds the retrieval results of a query are cached. The cache is disabled if 0
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u64))]
+    retrieval_cache_ttl: u64,
+    /// Maximum number of queries whose retrieval results are cached
+    #[arg(long, default_value = "256", value_parser = clap::value_parser!(usize))]
+    retrieval_cache_size: usize,
+    /// Return `debug.retrieval_explanation` in the chat completion responses in non-stream mode, explaining how each candidate chunk went through the retrieval
+    #[arg(long)]
+    debug_retrieval: bool,
+    /// Minimum number of characters of a retrieved chunk. Shorter chunks are dropped regardless of their scores
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(usize))]
+    min_chunk_chars: usize,
+    /// Select the retrieved chunks by maximal marginal relevance (MMR) to diversify the context
+    #[arg(long)]
+    enable_mmr: bool,
+    /// Trade-off between relevance and diversity for MMR, from 0.0 (diversity only) to 1.0 (relevance only)
+    #[arg(long, default_value = "0.5", value_parser = clap::value_parser!(f32))]
+    mmr_lambda: f32,
     /// Maximum number of tokens each chunk contains
     #[arg(long, default_value = "100", value_parser = clap::value_parser!(usize))]
     chunk_capacity: usize,
+    /// Detect the language of each chunk while creating embeddings, and store it in the `lang` field of the payload
+    #[arg(long)]
+    detect_chunk_language: bool,
     /// Print prompt strings to stdout
     #[arg(long)]
     log_prompts: bool,
+    /// Print only the ids and scores of the retrieved chunks, never their text. Overrides the prompt log.
+    #[arg(long)]
+    log_chunk_ids_only: bool,
     /// Print statistics to stdout
     #[arg(long)]
     log_stat: bool,
@@ -97,9 +179,24 @@ struct Cli {
     /// Socket address of LlamaEdge API Server instance
     #[arg(long, default_value = DEFAULT_SOCKET_ADDRESS)]
     socket_addr: String,
+    /// API key required in the `Authorization: Bearer <key>` header of the requests to the `/v1` endpoints. No authentication if not set
+    #[arg(long, env = "API_KEY", hide_env_values = true)]
+    api_key: Option<String>,
+    /// Number of seconds to wait for in-flight requests to complete after a shutdown signal
+    #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64))]
+    shutdown_timeout: u64,
+    /// Origins allowed to make cross-origin requests, separated by comma without space, for example, '--cors-allowed-origins https://a.com,https://b.com'. `*` allows all origins.
+    #[arg(long, value_delimiter = ',')]
+    cors_allowed_origins: Vec<String>,
+    /// Number of seconds browsers may cache the result of a CORS preflight request
+    #[arg(long, default_value = "600", value_parser = clap::value_parser!(u64))]
+    cors_max_age: u64,
     /// Root path for the Web UI files
     #[arg(long, default_value = "chatbot-ui")]
     web_ui: PathBuf,
+    /// Print the JSON schema of the CLI options and exit
+    #[arg(long, exclusive = true)]
+    print_config_schema: bool,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -112,7 +209,13 @@ async fn main() -> Result<(), ServerError> {
     };
 
     // parse the command line arguments
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    if matches.get_flag("print_config_schema") {
+        println!("{:#}", config_schema());
+        return Ok(());
+    }
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let param_sources = param_sources(&matches);
 
     // log the version of the server
     let server_version = env!("CARGO_PKG_VERSION").to_string();
@@ -145,6 +248,7 @@ async fn main() -> Result<(), ServerError> {
             "LlamaEdge RAG API server requires two context sizes: one for chat model, one for embedding model.".to_owned(),
         ));
     }
+    check_ctx_sizes(&cli.ctx_size)?;
     let ctx_sizes_str: String = cli
         .ctx_size
         .iter()
@@ -174,6 +278,31 @@ async fn main() -> Result<(), ServerError> {
     if let Some(reverse_prompt) = &cli.reverse_prompt {
         log(format!("[INFO] reverse prompt: {}", reverse_prompt));
     }
+    log(format!(
+        "[INFO] Max embedding inputs: {}",
+        &cli.max_embedding_inputs
+    ));
+    if cli.embedding_batch_size == 0 {
+        return Err(ServerError::ArgumentError(
+            "The embedding batch size should be greater than 0.".to_string(),
+        ));
+    }
+    log(format!(
+        "[INFO] Embedding batch size: {}",
+        &cli.embedding_batch_size
+    ));
+    log(format!(
+        "[INFO] Require model field: {}",
+        &cli.require_model_field
+    ));
+    log(format!(
+        "[INFO] Max number of batch requests: {}",
+        &cli.max_batch_requests
+    ));
+    log(format!(
+        "[INFO] Max number of stop sequences: {}",
+        &cli.max_stop_sequences
+    ));
 
     if let Some(rag_prompt) = &cli.rag_prompt {
         log(format!("[INFO] rag prompt: {}", rag_prompt));
@@ -193,14 +322,50 @@ async fn main() -> Result<(), ServerError> {
         "[INFO] Qdrant collection name: {}",
         &cli.qdrant_collection_name
     ));
+    if cli.qdrant_limit > cli.max_qdrant_limit {
+        return Err(ServerError::ArgumentError(format!(
+            "The Qdrant limit should not exceed the max Qdrant limit {}, but got {}.",
+            cli.max_qdrant_limit, cli.qdrant_limit
+        )));
+    }
     log(format!(
         "[INFO] Max number of retrieved result: {}",
         &cli.qdrant_limit
     ));
+    log(format!(
+        "[INFO] Max Qdrant limit: {}",
+        &cli.max_qdrant_limit
+    ));
     log(format!(
         "[INFO] Qdrant score threshold: {}",
         &cli.qdrant_score_threshold
     ));
+    log(format!(
+        "[INFO] Retrieval cache TTL: {}s",
+        &cli.retrieval_cache_ttl
+    ));
+    if cli.retrieval_cache_ttl > 0 {
+        log(format!(
+            "[INFO] Retrieval cache size: {}",
+            &cli.retrieval_cache_size
+        ));
+    }
+    log(format!("[INFO] Min chunk chars: {}", &cli.min_chunk_chars));
+    log(format!("[INFO] Debug retrieval: {}", &cli.debug_retrieval));
+    log(format!("[INFO] Enable MMR: {}", &cli.enable_mmr));
+    if cli.enable_mmr {
+        if !(0.0..=1.0).contains(&cli.mmr_lambda) {
+            return Err(ServerError::ArgumentError(format!(
+                "The MMR lambda should be between 0.0 and 1.0, but got {}.",
+                cli.mmr_lambda
+            )));
+        }
+        log(format!("[INFO] MMR lambda: {}", &cli.mmr_lambda));
+    }
+    log(format!(
+        "[INFO] Qdrant outage behavior: {}",
+        &cli.qdrant_outage_behavior
+    ));
     let qdrant_config = QdrantConfig {
         url: cli.qdrant_url,
         collection_name: cli.qdrant_collection_name,
@@ -208,13 +373,73 @@ async fn main() -> Result<(), ServerError> {
         score_threshold: cli.qdrant_score_threshold,
     };
 
+    // the syntax of the url is checked above; now check that the server is reachable
+    let qdrant_connect_timeout = Duration::from_secs(cli.qdrant_connect_timeout);
+    log(format!(
+        "[INFO] Qdrant connect retries: {}",
+        &cli.qdrant_connect_retries
+    ));
+    log(format!(
+        "[INFO] Qdrant connect timeout: {}s",
+        &cli.qdrant_connect_timeout
+    ));
+    wait_for_qdrant(
+        &qdrant_config,
+        cli.qdrant_connect_retries,
+        qdrant_connect_timeout,
+    )
+    .await?;
+
     log(format!(
         "[INFO] Chunk capacity (in tokens): {}",
         &cli.chunk_capacity
     ));
-    log(format!("[INFO] Enable prompt log: {}", &cli.log_prompts));
+    // never print the prompt if the chunk text must stay out of the log
+    let log_prompts = match cli.log_chunk_ids_only {
+        true => {
+            if cli.log_prompts || cli.log_all {
+                println!("       * [WARNING] The prompt log is disabled, since the '--log-chunk-ids-only' option is set.");
+            }
+            false
+        }
+        false => cli.log_prompts || cli.log_all,
+    };
+    log(format!(
+        "[INFO] Detect chunk language: {}",
+        &cli.detect_chunk_language
+    ));
+    log(format!("[INFO] Enable prompt log: {}", log_prompts));
+    log(format!(
+        "[INFO] Log chunk ids only: {}",
+        &cli.log_chunk_ids_only
+    ));
     log(format!("[INFO] Enable plugin log: {}", &cli.log_stat));
     log(format!("[INFO] Socket address: {}", &cli.socket_addr));
+    if let Some(api_key) = &cli.api_key {
+        if api_key.is_empty() {
+            return Err(ServerError::ArgumentError(
+                "The API key should not be empty.".to_string(),
+            ));
+        }
+        log("[INFO] API key: set");
+        API_KEY
+            .set(api_key.clone())
+            .map_err(|_| ServerError::Operation("Failed to set `API_KEY`.".to_string()))?;
+    }
+    log(format!(
+        "[INFO] Shutdown timeout: {}s",
+        &cli.shutdown_timeout
+    ));
+    if !cli.cors_allowed_origins.is_empty() {
+        log(format!(
+            "[INFO] CORS allowed origins: {}",
+            cli.cors_allowed_origins.join(",")
+        ));
+    }
+    log(format!("[INFO] CORS max age: {}", &cli.cors_max_age));
+    CORS_MAX_AGE
+        .set(cli.cors_max_age)
+        .map_err(|_| ServerError::Operation("Failed to set `CORS_MAX_AGE`.".to_string()))?;
 
     // RAG policy
     let mut policy = cli.policy;
@@ -225,6 +450,25 @@ async fn main() -> Result<(), ServerError> {
         log(format!("       * Updated RAG policy: {}", policy));
     }
 
+    log(format!(
+        "[INFO] Allow raw prompt: {}",
+        &cli.allow_raw_prompt
+    ));
+    log(format!(
+        "[INFO] Stream retrieval events: {}",
+        &cli.stream_retrieval_events
+    ));
+    log(format!("[INFO] Timing headers: {}", &cli.timing_headers));
+    if let Some(grounding_score) = &cli.grounding_score {
+        log(format!(
+            "[INFO] Grounding score aggregation: {}",
+            grounding_score
+        ));
+    }
+    if let Some(max_response_bytes) = &cli.max_response_bytes {
+        log(format!("[INFO] Max response bytes: {}", max_response_bytes));
+    }
+
     // create metadata for chat model
     let chat_metadata = MetadataBuilder::new(
         cli.model_name[0].clone(),
@@ -234,13 +478,14 @@ async fn main() -> Result<(), ServerError> {
     .with_ctx_size(cli.ctx_size[0])
     .with_reverse_prompt(cli.reverse_prompt)
     .with_batch_size(cli.batch_size[0])
-    .enable_prompts_log(cli.log_prompts || cli.log_all)
+    .enable_prompts_log(log_prompts)
     .enable_plugin_log(cli.log_stat || cli.log_all)
     .enable_debug_log(plugin_debug)
     .build();
 
     let chat_model_info = ModelConfig {
         name: chat_metadata.model_name.clone(),
+        alias: chat_metadata.model_alias.clone(),
         ty: "chat".to_string(),
         prompt_template: chat_metadata.prompt_template,
         n_predict: chat_metadata.n_predict,
@@ -266,13 +511,14 @@ async fn main() -> Result<(), ServerError> {
     )
     .with_ctx_size(cli.ctx_size[1])
     .with_batch_size(cli.batch_size[1])
-    .enable_prompts_log(cli.log_prompts || cli.log_all)
+    .enable_prompts_log(log_prompts)
     .enable_plugin_log(cli.log_stat || cli.log_all)
     .enable_debug_log(plugin_debug)
     .build();
 
     let embedding_model_info = ModelConfig {
         name: embedding_metadata.model_name.clone(),
+        alias: embedding_metadata.model_alias.clone(),
         ty: "embedding".to_string(),
         prompt_template: embedding_metadata.prompt_template,
         n_predict: embedding_metadata.n_predict,
@@ -295,6 +541,27 @@ async fn main() -> Result<(), ServerError> {
         chat_model: chat_model_info,
         embedding_model: embedding_model_info,
         policy: cli.policy,
+        log_chunk_ids_only: cli.log_chunk_ids_only,
+        grounding_score: cli.grounding_score,
+        qdrant_outage_behavior: cli.qdrant_outage_behavior,
+        stream_retrieval_events: cli.stream_retrieval_events,
+        max_stop_sequences: cli.max_stop_sequences,
+        require_model_field: cli.require_model_field,
+        max_batch_requests: cli.max_batch_requests,
+        enable_mmr: cli.enable_mmr,
+        mmr_lambda: cli.mmr_lambda,
+        timing_headers: cli.timing_headers,
+        detect_chunk_language: cli.detect_chunk_language,
+        max_response_bytes: cli.max_response_bytes,
+        min_chunk_chars: cli.min_chunk_chars,
+        retrieval_cache_ttl: cli.retrieval_cache_ttl,
+        retrieval_cache_size: cli.retrieval_cache_size,
+        max_embedding_inputs: cli.max_embedding_inputs,
+        embedding_batch_size: cli.embedding_batch_size,
+        allow_raw_prompt: cli.allow_raw_prompt,
+        debug_retrieval: cli.debug_retrieval,
+        qdrant_connect_timeout: cli.qdrant_connect_timeout,
+        max_qdrant_limit: cli.max_qdrant_limit,
     };
 
     // initialize the core context
@@ -328,39 +595,361 @@ async fn main() -> Result<(), ServerError> {
         port,
         rag_config,
         qdrant_config,
+        param_sources,
     };
     SERVER_INFO
         .set(server_info)
         .map_err(|_| ServerError::Operation("Failed to set `SERVER_INFO`.".to_string()))?;
 
+    let shutdown_timeout = Duration::from_secs(cli.shutdown_timeout);
     let new_service = make_service_fn(move |_| {
         let web_ui = cli.web_ui.to_string_lossy().to_string();
         let chunk_capacity = cli.chunk_capacity;
+        let cors_allowed_origins = cli.cors_allowed_origins.clone();
 
         async move {
             Ok::<_, Error>(service_fn(move |req| {
-                handle_request(req, chunk_capacity, web_ui.clone())
+                handle_request(
+                    req,
+                    chunk_capacity,
+                    web_ui.clone(),
+                    cors_allowed_origins.clone(),
+                    qdrant_connect_timeout,
+                )
             }))
         }
     });
-    let server = Server::bind(&addr).serve(new_service);
+    // stop accepting new connections on a shutdown signal, and let the in-flight requests drain
+    let shutdown = Arc::new(Notify::new());
+    let signaled = shutdown.clone();
+    let server = Server::bind(&addr)
+        .serve(new_service)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            signaled.notify_one();
+        });
     log(format!(
         "[INFO] LlamaEdge-RAG API server listening on http://{}:{}",
         addr.ip(),
         addr.port()
     ));
 
-    match server.await {
-        Ok(_) => Ok(()),
-        Err(e) => Err(ServerError::Operation(e.to_string())),
+    let drain_timeout = async {
+        shutdown.notified().await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    tokio::select! {
+        res = server => match res {
+            Ok(_) => {
+                log("[INFO] LlamaEdge-RAG API server shut down");
+                Ok(())
+            }
+            Err(e) => Err(ServerError::Operation(e.to_string())),
+        },
+        _ = drain_timeout => {
+            log(format!(
+                "[INFO] LlamaEdge-RAG API server shut down after the shutdown timeout of {}s, aborting the in-flight requests",
+                shutdown_timeout.as_secs()
+            ));
+            Ok(())
+        }
     }
 }
 
+/// Wait for a shutdown signal: SIGINT (Ctrl-C), or SIGTERM on unix. Signals are not available on other targets, such as wasm32-wasi, where the future never completes.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        #[cfg(any(unix, windows))]
+        match tokio::signal::ctrl_c().await {
+            Ok(_) => {}
+            Err(e) => {
+                log(format!("[WARNING] Failed to listen for Ctrl-C. {}", e));
+                futures::future::pending::<()>().await
+            }
+        }
+
+        #[cfg(not(any(unix, windows)))]
+        futures::future::pending::<()>().await
+    };
+
+    let terminate = async {
+        #[cfg(unix)]
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                log(format!("[WARNING] Failed to listen for SIGTERM. {}", e));
+                futures::future::pending::<()>().await
+            }
+        }
+
+        #[cfg(not(unix))]
+        futures::future::pending::<()>().await
+    };
+
+    let signal = tokio::select! {
+        _ = ctrl_c => "SIGINT",
+        _ = terminate => "SIGTERM",
+    };
+    log(format!(
+        "[INFO] Received {}. Shutting down LlamaEdge-RAG API server ...",
+        signal
+    ));
+}
+
+// the types of the values of CLI options typed `integer` in the config schema
+const INTEGER_TYPES: [fn() -> TypeId; 12] = [
+    TypeId::of::<u8>,
+    TypeId::of::<u16>,
+    TypeId::of::<u32>,
+    TypeId::of::<u64>,
+    TypeId::of::<usize>,
+    TypeId::of::<i8>,
+    TypeId::of::<i16>,
+    TypeId::of::<i32>,
+    TypeId::of::<i64>,
+    TypeId::of::<isize>,
+    TypeId::of::<u128>,
+    TypeId::of::<i128>,
+];
+
+/// The JSON schema type of a single value of the CLI option, or `None` if the type of its values is not mapped.
+fn value_type(arg: &Arg) -> Option<&'static str> {
+    if matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse) {
+        return Some("boolean");
+    }
+
+    let type_id = arg.get_value_parser().type_id();
+    if INTEGER_TYPES.iter().any(|integer| type_id == integer()) {
+        Some("integer")
+    } else if type_id == TypeId::of::<f32>() || type_id == TypeId::of::<f64>() {
+        Some("number")
+    } else if type_id == TypeId::of::<String>()
+        || type_id == TypeId::of::<PathBuf>()
+        || !arg.get_possible_values().is_empty()
+    {
+        Some("string")
+    } else {
+        None
+    }
+}
+
+/// Build the JSON schema of the CLI options, keyed by the long names of the options, with their types, defaults and allowed values.
+fn config_schema() -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    let mut required = vec![];
+    for arg in Cli::command().get_arguments() {
+        let name = match arg.get_long() {
+            Some(name) if name != "print-config-schema" => name,
+            _ => continue,
+        };
+
+        let multiple = matches!(arg.get_action(), ArgAction::Append);
+        let mut schema = serde_json::Map::new();
+        if let Some(help) = arg.get_help() {
+            schema.insert("description".to_string(), help.to_string().into());
+        }
+
+        // the type of a single value
+        let value_type = value_type(arg).unwrap_or("string");
+        let mut item = serde_json::Map::new();
+        item.insert("type".to_string(), value_type.into());
+        let possible_values: Vec<serde_json::Value> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| value_type == "string" && !value.is_hide_set())
+            .map(|value| value.get_name().into())
+            .collect();
+        if !possible_values.is_empty() {
+            item.insert("enum".to_string(), possible_values.into());
+        }
+
+        let defaults: Vec<serde_json::Value> = arg
+            .get_default_values()
+            .iter()
+            .flat_map(|value| {
+                let value = value.to_string_lossy().to_string();
+                match arg.get_value_delimiter() {
+                    Some(delimiter) => value.split(delimiter).map(str::to_string).collect(),
+                    None => vec![value],
+                }
+            })
+            .map(|value| match value_type {
+                "boolean" | "integer" | "number" => {
+                    serde_json::from_str(&value).unwrap_or(value.into())
+                }
+                _ => value.into(),
+            })
+            .collect();
+
+        match multiple {
+            true => {
+                schema.insert("type".to_string(), "array".into());
+                schema.insert("items".to_string(), item.into());
+                if !defaults.is_empty() {
+                    schema.insert("default".to_string(), defaults.into());
+                }
+            }
+            false => {
+                schema.extend(item);
+                if let Some(default) = defaults.into_iter().next() {
+                    schema.insert("default".to_string(), default);
+                }
+            }
+        }
+        if let Some(env) = arg.get_env() {
+            schema.insert("x-env".to_string(), env.to_string_lossy().into());
+        }
+
+        if arg.is_required_set() {
+            required.push(name.to_string());
+        }
+        properties.insert(name.to_string(), schema.into());
+    }
+
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "LlamaEdge-RAG API Server",
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// Check that the context sizes of the chat and embedding models are at least `MIN_CTX_SIZE`.
+fn check_ctx_sizes(ctx_sizes: &[u64]) -> Result<(), ServerError> {
+    for (model, ctx_size) in ["chat", "embedding"].iter().zip(ctx_sizes.iter()) {
+        if *ctx_size < MIN_CTX_SIZE {
+            return Err(ServerError::ArgumentError(format!(
+                "The context size of the {} model should be at least {}, but got {}.",
+                model, MIN_CTX_SIZE, ctx_size
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Wait until the Qdrant server is reachable, retrying with exponential backoff.
+async fn wait_for_qdrant(
+    qdrant_config: &QdrantConfig,
+    retries: u32,
+    timeout: Duration,
+) -> Result<(), ServerError> {
+    let mut backoff = QDRANT_CONNECT_INITIAL_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        match retrieval::probe_qdrant(qdrant_config, timeout).await {
+            Ok(true) => {
+                log(format!(
+                    "[INFO] Qdrant collection `{}` is reachable",
+                    qdrant_config.collection_name
+                ));
+                return Ok(());
+            }
+            Ok(false) => {
+                log(format!(
+                    "[INFO] Qdrant server is reachable, but the collection `{}` does not exist yet. It is created when the first document is ingested.",
+                    qdrant_config.collection_name
+                ));
+                return Ok(());
+            }
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                log(format!(
+                    "[WARNING] Failed to reach the Qdrant server at {}. {} Retry {}/{} in {}s.",
+                    qdrant_config.url,
+                    e,
+                    attempt,
+                    retries,
+                    backoff.as_secs()
+                ));
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(QDRANT_CONNECT_MAX_BACKOFF);
+            }
+            Err(e) => {
+                return Err(ServerError::QdrantConnection(format!(
+                    "{} is not reachable after {} attempt(s). {}",
+                    qdrant_config.url,
+                    attempt + 1,
+                    e
+                )));
+            }
+        }
+    }
+}
+
+/// Report whether the Qdrant server is reachable: `200 OK` if so, `503 Service Unavailable` otherwise.
+async fn health_response(timeout: Duration) -> Response<Body> {
+    let (status, body) = match SERVER_INFO.get() {
+        Some(server_info) => {
+            match retrieval::probe_qdrant(&server_info.qdrant_config, timeout).await {
+                Ok(_) => (StatusCode::OK, serde_json::json!({ "status": "ok" })),
+                Err(e) => (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    serde_json::json!({
+                        "status": "unavailable",
+                        "error": format!("Qdrant is not reachable. {}", e),
+                    }),
+                ),
+            }
+        }
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            serde_json::json!({
+                "status": "unavailable",
+                "error": "The server info is not set.",
+            }),
+        ),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Resolve where the value of each CLI option comes from: `cli` if it is given on the command line, `env` if it is read from an environment variable, or `default` otherwise.
+fn param_sources(matches: &ArgMatches) -> BTreeMap<String, String> {
+    let mut sources = BTreeMap::new();
+    for arg in Cli::command().get_arguments() {
+        let name = match arg.get_long() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let source = match matches.value_source(arg.get_id().as_str()) {
+            Some(ValueSource::CommandLine) => "cli",
+            Some(ValueSource::EnvVariable) => "env",
+            _ => "default",
+        };
+        sources.insert(name, source.to_string());
+    }
+    sources
+}
+
 async fn handle_request(
     req: Request<Body>,
     chunk_capacity: usize,
     web_ui: String,
+    cors_allowed_origins: Vec<String>,
+    qdrant_connect_timeout: Duration,
 ) -> Result<Response<Body>, hyper::Error> {
+    // the value of the `Access-Control-Allow-Origin` header, if CORS origins are configured
+    let cors = match cors_allowed_origins.is_empty() {
+        true => None,
+        false => Some(allowed_origin(
+            &cors_allowed_origins,
+            req.headers().get(header::ORIGIN),
+        )),
+    };
+    if let Some(allow_origin) = &cors {
+        if req.method() == Method::OPTIONS {
+            return Ok(cors_preflight_response(allow_origin.clone()));
+        }
+    }
+
     let path_str = req.uri().path();
     let path_buf = PathBuf::from(path_str);
     let mut path_iter = path_buf.iter();
@@ -368,11 +957,105 @@ async fn handle_request(
     let root_path = path_iter.next().unwrap_or_default();
     let root_path = "/".to_owned() + root_path.to_str().unwrap_or_default();
 
-    match root_path.as_str() {
-        "/echo" => Ok(Response::new(Body::from("echo test"))),
-        "/v1" => backend::handle_llama_request(req, chunk_capacity).await,
-        _ => Ok(static_response(path_str, web_ui)),
+    let mut response = match root_path.as_str() {
+        "/echo" => Response::new(Body::from("echo test")),
+        "/health" => health_response(qdrant_connect_timeout).await,
+        "/v1" => match authorized(&req) {
+            true => backend::handle_llama_request(req, chunk_capacity).await?,
+            false => error::unauthorized("Invalid or missing API key.")?,
+        },
+        _ => static_response(path_str, web_ui),
+    };
+
+    if let Some(allow_origin) = cors {
+        let headers = response.headers_mut();
+        // the response depends on the origin, unless all origins are allowed
+        if allow_origin
+            .as_ref()
+            .is_none_or(|allow_origin| allow_origin != "*")
+        {
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        match allow_origin {
+            Some(allow_origin) => {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+            }
+            None => {
+                headers.remove(header::ACCESS_CONTROL_ALLOW_ORIGIN);
+            }
+        }
+    }
+
+    Ok(response)
+}
+
+/// Check the `Authorization: Bearer <key>` header of a request against the API key. Always `true` if no API key is configured. CORS preflight requests carry no credentials, so they are not checked.
+fn authorized(req: &Request<Body>) -> bool {
+    let api_key = match API_KEY.get() {
+        Some(api_key) => api_key,
+        None => return true,
+    };
+    if req.method() == Method::OPTIONS {
+        return true;
+    }
+
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|key| constant_time_eq(key.trim().as_bytes(), api_key.as_bytes()))
+}
+
+/// The value of the `Access-Control-Allow-Headers` header of CORS preflight responses. The `*` wildcard does not cover the `Authorization` header, so the headers are listed if an API key is required.
+pub(crate) fn cors_allow_headers(api_key_required: bool) -> &'static str {
+    match api_key_required {
+        true => "Authorization, Content-Type",
+        false => "*",
+    }
+}
+
+/// Resolve the value of the `Access-Control-Allow-Origin` header for the `Origin` header of a request: `*` if all origins are allowed, the origin of the request if it is in the allowed origins, or `None` otherwise.
+fn allowed_origin(
+    cors_allowed_origins: &[String],
+    origin: Option<&HeaderValue>,
+) -> Option<HeaderValue> {
+    if cors_allowed_origins.iter().any(|allowed| allowed == "*") {
+        return Some(HeaderValue::from_static("*"));
+    }
+
+    let origin = origin?;
+    let requested = origin.to_str().ok()?;
+    cors_allowed_origins
+        .iter()
+        .any(|allowed| allowed.trim_end_matches('/') == requested)
+        .then(|| origin.clone())
+}
+
+/// Respond to a CORS preflight request. The `Access-Control-*` headers are omitted if the origin is not allowed.
+fn cors_preflight_response(allow_origin: Option<HeaderValue>) -> Response<Body> {
+    let mut builder = Response::builder().status(StatusCode::NO_CONTENT);
+    // a disallowed origin implies a specific list of allowed origins
+    if allow_origin
+        .as_ref()
+        .is_none_or(|allow_origin| allow_origin != "*")
+    {
+        builder = builder.header(header::VARY, "Origin");
+    }
+    if let Some(allow_origin) = allow_origin {
+        builder = builder
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin)
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, "*")
+            .header(
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                cors_allow_headers(API_KEY.get().is_some()),
+            )
+            .header(
+                header::ACCESS_CONTROL_MAX_AGE,
+                CORS_MAX_AGE.get().copied().unwrap_or_default(),
+            );
     }
+
+    builder.body(Body::empty()).unwrap()
 }
 
 fn static_response(path_str: &str, root: String) -> Response<Body> {
@@ -412,6 +1095,8 @@ pub(crate) struct QdrantConfig {
 pub(crate) struct ModelConfig {
     // model name
     name: String,
+    // model alias
+    alias: String,
     // type: chat or embedding
     #[serde(rename = "type")]
     ty: String,
@@ -437,6 +1122,8 @@ pub(crate) struct ServerInfo {
     // models: Vec<ModelConfig>,
     rag_config: RagConfig,
     qdrant_config: QdrantConfig,
+    // source of each CLI option
+    param_sources: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -444,4 +1131,238 @@ pub(crate) struct RagConfig {
     pub chat_model: ModelConfig,
     pub embedding_model: ModelConfig,
     pub policy: MergeRagContextPolicy,
+    pub log_chunk_ids_only: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grounding_score: Option<GroundingScoreAggregation>,
+    pub qdrant_outage_behavior: QdrantOutageBehavior,
+    pub stream_retrieval_events: bool,
+    pub max_stop_sequences: usize,
+    pub require_model_field: bool,
+    pub max_batch_requests: usize,
+    pub enable_mmr: bool,
+    pub mmr_lambda: f32,
+    pub timing_headers: bool,
+    pub detect_chunk_language: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_response_bytes: Option<usize>,
+    pub min_chunk_chars: usize,
+    pub retrieval_cache_ttl: u64,
+    pub retrieval_cache_size: usize,
+    pub max_embedding_inputs: usize,
+    pub embedding_batch_size: usize,
+    pub allow_raw_prompt: bool,
+    pub debug_retrieval: bool,
+    pub qdrant_connect_timeout: u64,
+    pub max_qdrant_limit: u64,
+}
+
+/// Method for aggregating the scores of the chunks injected into the prompt into a single grounding score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum GroundingScoreAggregation {
+    /// The highest score among the injected chunks
+    Max,
+    /// The mean score of the injected chunks
+    Mean,
+}
+impl GroundingScoreAggregation {
+    /// Aggregate the given chunk scores. Returns `0.0` if no chunk is injected.
+    pub(crate) fn aggregate(&self, scores: &[f32]) -> f32 {
+        if scores.is_empty() {
+            return 0.0;
+        }
+
+        match self {
+            GroundingScoreAggregation::Max => scores.iter().copied().fold(f32::MIN, f32::max),
+            GroundingScoreAggregation::Mean => scores.iter().sum::<f32>() / scores.len() as f32,
+        }
+    }
+}
+impl std::fmt::Display for GroundingScoreAggregation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GroundingScoreAggregation::Max => write!(f, "max"),
+            GroundingScoreAggregation::Mean => write!(f, "mean"),
+        }
+    }
+}
+
+/// Behavior of chat completions while the Qdrant server is unavailable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QdrantOutageBehavior {
+    /// Return an error for each chat completion request
+    #[default]
+    Fail,
+    /// Answer without retrieval, and resume retrieval once Qdrant recovers
+    Degrade,
+}
+impl std::fmt::Display for QdrantOutageBehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QdrantOutageBehavior::Fail => write!(f, "fail"),
+            QdrantOutageBehavior::Degrade => write!(f, "degrade"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grounding_score_aggregation() {
+        let scores = [0.9, 0.5, 0.7];
+        assert_eq!(GroundingScoreAggregation::Max.aggregate(&scores), 0.9);
+        assert!((GroundingScoreAggregation::Mean.aggregate(&scores) - 0.7).abs() < 1e-6);
+
+        // no chunk injected
+        assert_eq!(GroundingScoreAggregation::Max.aggregate(&[]), 0.0);
+        assert_eq!(GroundingScoreAggregation::Mean.aggregate(&[]), 0.0);
+    }
+
+    #[test]
+    fn test_check_ctx_sizes() {
+        assert!(check_ctx_sizes(&[4096, 384]).is_ok());
+        assert!(check_ctx_sizes(&[MIN_CTX_SIZE, MIN_CTX_SIZE]).is_ok());
+
+        let e = check_ctx_sizes(&[4096, MIN_CTX_SIZE - 1]).unwrap_err();
+        assert!(e.to_string().contains("embedding model"));
+        let e = check_ctx_sizes(&[0, 384]).unwrap_err();
+        assert!(e.to_string().contains("chat model"));
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let origins = |origins: &[&str]| -> Vec<String> {
+            origins.iter().map(|origin| origin.to_string()).collect()
+        };
+        let origin = HeaderValue::from_static("https://a.com");
+
+        // all origins are allowed, even without an `Origin` header
+        let all = origins(&["https://b.com", "*"]);
+        assert_eq!(allowed_origin(&all, Some(&origin)).unwrap(), "*");
+        assert_eq!(allowed_origin(&all, None).unwrap(), "*");
+
+        // a trailing slash of an allowed origin is ignored
+        let specific = origins(&["https://a.com/", "https://b.com"]);
+        assert_eq!(
+            allowed_origin(&specific, Some(&origin)).unwrap(),
+            "https://a.com"
+        );
+        assert!(
+            allowed_origin(&specific, Some(&HeaderValue::from_static("https://c.com"))).is_none()
+        );
+        assert!(allowed_origin(
+            &specific,
+            Some(&HeaderValue::from_static("https://a.com.evil"))
+        )
+        .is_none());
+        assert!(allowed_origin(&specific, None).is_none());
+    }
+
+    #[test]
+    fn test_cors_allow_headers() {
+        // the wildcard does not cover `Authorization`, so the Bearer key must be listed
+        let allow_headers: Vec<&str> = cors_allow_headers(true).split(", ").collect();
+        assert!(allow_headers.contains(&"Authorization"));
+        assert!(allow_headers.contains(&"Content-Type"));
+        assert_eq!(cors_allow_headers(false), "*");
+    }
+
+    #[test]
+    fn test_cors_preflight_response() {
+        // an allowed origin is echoed back
+        let response = cors_preflight_response(Some(HeaderValue::from_static("https://a.com")));
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://a.com"
+        );
+        assert_eq!(response.headers()[header::VARY], "Origin");
+
+        // no CORS headers for a disallowed origin, but the response still varies by origin
+        let response = cors_preflight_response(None);
+        assert_eq!(response.headers()[header::VARY], "Origin");
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_MAX_AGE));
+        assert!(!response
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+
+    #[test]
+    fn test_value_types() {
+        // an option whose values are of an unmapped type would be typed `string` in the config schema
+        for arg in Cli::command().get_arguments() {
+            assert!(
+                value_type(arg).is_some(),
+                "the type of the values of `{}` is not mapped",
+                arg.get_id()
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_schema() {
+        let schema = config_schema();
+        let properties = schema["properties"].as_object().unwrap();
+
+        // no option with a numeric default is typed `string`
+        for (name, property) in properties {
+            let (value_type, default) = match property["type"].as_str() {
+                Some("array") => (&property["items"]["type"], &property["default"][0]),
+                _ => (&property["type"], &property["default"]),
+            };
+            if let Some(default) = default.as_str() {
+                assert!(
+                    value_type != "string" || default.parse::<f64>().is_err(),
+                    "{} is typed string",
+                    name
+                );
+            }
+        }
+
+        let limit = &properties["qdrant-limit"];
+        assert_eq!(limit["type"], "integer");
+        assert_eq!(limit["default"], 5);
+        assert_eq!(properties["mmr-lambda"]["type"], "number");
+        assert_eq!(properties["ctx-size"]["items"]["type"], "integer");
+        assert_eq!(
+            properties["ctx-size"]["default"],
+            serde_json::json!([4096, 384])
+        );
+        assert_eq!(properties["enable-mmr"]["type"], "boolean");
+
+        // the prompt templates are listed as the allowed values
+        let prompt_template = &properties["prompt-template"];
+        assert_eq!(prompt_template["type"], "string");
+        let templates = prompt_template["enum"].as_array().unwrap();
+        assert!(templates.contains(&"llama-2-chat".into()));
+        assert!(templates.contains(&"chatml".into()));
+        assert!(schema["required"]
+            .as_array()
+            .unwrap()
+            .contains(&"prompt-template".into()));
+    }
+
+    #[test]
+    fn test_param_sources() {
+        let matches = Cli::command().get_matches_from([
+            "rag-api-server",
+            "--model-name",
+            "Llama-2-7b,all-minilm",
+            "--prompt-template",
+            "llama-2-chat",
+            "--qdrant-limit",
+            "3",
+        ]);
+        let sources = param_sources(&matches);
+
+        assert_eq!(sources["model-name"], "cli");
+        assert_eq!(sources["qdrant-limit"], "cli");
+        assert_eq!(sources["qdrant-score-threshold"], "default");
+        assert_eq!(sources["socket-addr"], "default");
+    }
 }
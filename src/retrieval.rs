@@ -0,0 +1,1055 @@
+use crate::{QdrantConfig, RagConfig};
+use endpoints::embeddings::EmbeddingObject;
+use once_cell::sync::Lazy;
+use qdrant::{Point, PointId, Qdrant, ScoredPoint};
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A point retrieved from the Qdrant collection.
+#[derive(Debug, Clone)]
+pub(crate) struct RetrievedPoint {
+    /// Id of the point
+    pub(crate) id: String,
+    /// Text of the chunk stored in the `source` field of the payload
+    pub(crate) source: String,
+    /// Similarity score against the query embedding
+    pub(crate) score: f32,
+    /// Vector of the point
+    pub(crate) vector: Option<Vec<f32>>,
+}
+
+impl RetrievedPoint {
+    /// Number of characters of the chunk, ignoring the leading and trailing whitespaces.
+    pub(crate) fn num_chars(&self) -> usize {
+        // `source` keeps the quotes and escapes of the JSON string in the payload
+        match serde_json::from_str::<String>(&self.source) {
+            Ok(text) => text.trim().chars().count(),
+            Err(_) => self.source.trim().chars().count(),
+        }
+    }
+}
+
+/// Number of candidates fetched from Qdrant for each chunk selected by maximal marginal relevance.
+const MMR_CANDIDATES_FACTOR: u64 = 4;
+
+/// Explanation of how a candidate chunk went through the retrieval pipeline.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ChunkExplanation {
+    /// Id of the point
+    pub(crate) id: String,
+    /// Raw similarity score returned by Qdrant
+    pub(crate) score: f32,
+    /// Whether the score reaches the score threshold
+    pub(crate) passed_threshold: bool,
+    /// Whether the chunk is selected by maximal marginal relevance. Absent if MMR is disabled or the chunk is dropped before the selection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) survived_mmr: Option<bool>,
+    /// Stage dropping the chunk, if the chunk is not injected into the prompt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) dropped_by: Option<DropReason>,
+}
+
+/// Stage of the retrieval pipeline dropping a candidate chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DropReason {
+    /// The score is below the score threshold
+    ScoreThreshold,
+    /// Not selected by maximal marginal relevance
+    Mmr,
+    /// Shorter than `--min-chunk-chars`
+    MinChunkChars,
+}
+
+/// Retrieve the points for the query embedding from the Qdrant collection.
+///
+/// If MMR is enabled, more candidates than `limit` are fetched, and `limit` of them are selected by maximal marginal relevance. The chunks shorter than `--min-chunk-chars` are dropped before the selection, so that they do not take the place of useful chunks.
+///
+/// If `explanations` is given, it is filled with an explanation for each candidate. To explain the candidates below the score threshold, the threshold is then applied to the candidates instead of by Qdrant, which retrieves the same points.
+pub(crate) async fn retrieve_points(
+    query_embedding: &[f32],
+    qdrant_config: &QdrantConfig,
+    rag_config: &RagConfig,
+    explanations: Option<&mut Vec<ChunkExplanation>>,
+) -> Result<Vec<RetrievedPoint>, String> {
+    let candidate_config = QdrantConfig {
+        limit: match rag_config.enable_mmr {
+            true => qdrant_config.limit.saturating_mul(MMR_CANDIDATES_FACTOR),
+            false => qdrant_config.limit,
+        },
+        ..qdrant_config.clone()
+    };
+    let score_threshold = match explanations.is_some() {
+        true => None,
+        false => Some(qdrant_config.score_threshold),
+    };
+    let candidates = search_points(query_embedding, &candidate_config, score_threshold).await?;
+
+    Ok(select_points(
+        candidates,
+        qdrant_config,
+        rag_config.min_chunk_chars,
+        rag_config.enable_mmr.then_some(rag_config.mmr_lambda),
+        explanations,
+    ))
+}
+
+/// Select the points to inject into the prompt among the candidates: the candidates below the score threshold and those shorter than `min_chunk_chars` are dropped, and `limit` of the others are selected by maximal marginal relevance if `mmr_lambda` is given.
+///
+/// If `explanations` is given, it is filled with an explanation for each candidate.
+fn select_points(
+    mut candidates: Vec<RetrievedPoint>,
+    qdrant_config: &QdrantConfig,
+    min_chunk_chars: usize,
+    mmr_lambda: Option<f32>,
+    mut explanations: Option<&mut Vec<ChunkExplanation>>,
+) -> Vec<RetrievedPoint> {
+    if let Some(explanations) = explanations.as_deref_mut() {
+        *explanations = candidates
+            .iter()
+            .map(|point| {
+                let passed_threshold = point.score >= qdrant_config.score_threshold;
+                let dropped_by = match passed_threshold {
+                    false => Some(DropReason::ScoreThreshold),
+                    true if point.num_chars() < min_chunk_chars => Some(DropReason::MinChunkChars),
+                    true => None,
+                };
+                ChunkExplanation {
+                    id: point.id.clone(),
+                    score: point.score,
+                    passed_threshold,
+                    survived_mmr: None,
+                    dropped_by,
+                }
+            })
+            .collect();
+    }
+    candidates.retain(|point| point.score >= qdrant_config.score_threshold);
+
+    // drop the chunks too short to be useful, whatever their scores are
+    if min_chunk_chars > 0 {
+        let num_candidates = candidates.len();
+        candidates.retain(|point| point.num_chars() >= min_chunk_chars);
+        if candidates.len() < num_candidates {
+            println!(
+                "    * Dropped {} point(s) shorter than {} chars",
+                num_candidates - candidates.len(),
+                min_chunk_chars
+            );
+        }
+    }
+
+    let mmr_lambda = match mmr_lambda {
+        Some(mmr_lambda) => mmr_lambda,
+        None => return candidates,
+    };
+
+    let selected = mmr_select(candidates, qdrant_config.limit as usize, mmr_lambda);
+    if let Some(explanations) = explanations {
+        for explanation in explanations.iter_mut().filter(|e| e.dropped_by.is_none()) {
+            let survived = selected.iter().any(|point| point.id == explanation.id);
+            explanation.survived_mmr = Some(survived);
+            if !survived {
+                explanation.dropped_by = Some(DropReason::Mmr);
+            }
+        }
+    }
+
+    selected
+}
+
+/// Search the Qdrant collection for the points similar to the query embedding, with the score threshold if given.
+///
+/// Points are ordered by descending score, and points with equal scores by ascending id, so that the order is deterministic. Points without a `source` field in their payload are skipped.
+pub(crate) async fn search_points(
+    query_embedding: &[f32],
+    qdrant_config: &QdrantConfig,
+    score_threshold: Option<f32>,
+) -> Result<Vec<RetrievedPoint>, String> {
+    let qdrant_client = Qdrant::new_with_url(qdrant_config.url.clone());
+
+    let mut scored_points = qdrant_client
+        .search_points(
+            qdrant_config.collection_name.as_str(),
+            query_embedding.to_vec(),
+            qdrant_config.limit,
+            score_threshold,
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    sort_scored_points(&mut scored_points);
+
+    let mut points = vec![];
+    for point in scored_points {
+        if let Some(source) = point.payload.as_ref().and_then(|p| p.get("source")) {
+            points.push(RetrievedPoint {
+                id: point_id_to_string(&point.id),
+                source: source.to_string(),
+                score: point.score,
+                vector: point.vector,
+            });
+        }
+    }
+
+    Ok(points)
+}
+
+/// Select up to `k` points from the candidates by maximal marginal relevance.
+///
+/// Each step selects the candidate maximizing `lambda * score - (1 - lambda) * max_similarity`, where `score` is the relevance to the query and `max_similarity` is the highest cosine similarity to the points selected so far. `lambda = 1` ranks by relevance only, while `lambda = 0` favors diversity only.
+pub(crate) fn mmr_select(
+    mut candidates: Vec<RetrievedPoint>,
+    k: usize,
+    lambda: f32,
+) -> Vec<RetrievedPoint> {
+    let mut selected: Vec<RetrievedPoint> = Vec::with_capacity(k.min(candidates.len()));
+    while selected.len() < k && !candidates.is_empty() {
+        let mut best_idx = 0;
+        let mut best_mmr = f32::MIN;
+        for (idx, candidate) in candidates.iter().enumerate() {
+            let max_similarity = selected
+                .iter()
+                .map(|point| similarity(candidate, point))
+                .fold(0.0, f32::max);
+            let mmr = lambda * candidate.score - (1.0 - lambda) * max_similarity;
+            if mmr > best_mmr {
+                best_idx = idx;
+                best_mmr = mmr;
+            }
+        }
+        selected.push(candidates.remove(best_idx));
+    }
+
+    selected
+}
+
+// cosine similarity between the vectors of two points; 0 if any vector is missing
+fn similarity(a: &RetrievedPoint, b: &RetrievedPoint) -> f32 {
+    let (a, b) = match (&a.vector, &b.vector) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return 0.0,
+    };
+
+    let dot: f32 = a.iter().zip(b.iter()).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm_a == 0.0 || norm_b == 0.0 {
+        true => 0.0,
+        false => dot / (norm_a * norm_b),
+    }
+}
+
+/// Sort the points by descending score, breaking ties by ascending id.
+///
+/// Qdrant does not guarantee the order of points with equal scores, so the ids make the order deterministic.
+fn sort_scored_points(scored_points: &mut [ScoredPoint]) {
+    scored_points.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| compare_point_ids(&a.id, &b.id))
+    });
+}
+
+/// Compare point ids: numeric ids are ordered numerically and before uuids, which are ordered lexicographically.
+fn compare_point_ids(a: &PointId, b: &PointId) -> Ordering {
+    match (a, b) {
+        (PointId::Num(a), PointId::Num(b)) => a.cmp(b),
+        (PointId::Num(_), PointId::Uuid(_)) => Ordering::Less,
+        (PointId::Uuid(_), PointId::Num(_)) => Ordering::Greater,
+        (PointId::Uuid(a), PointId::Uuid(b)) => a.cmp(b),
+    }
+}
+
+fn point_id_to_string(id: &PointId) -> String {
+    match id {
+        PointId::Num(n) => n.to_string(),
+        PointId::Uuid(uuid) => uuid.clone(),
+    }
+}
+
+/// Number of consecutive Qdrant failures after which Qdrant is considered down.
+const OUTAGE_FAILURE_THRESHOLD: u32 = 3;
+/// Time to wait before probing Qdrant again once it is considered down.
+const OUTAGE_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+// circuit breaker tracking the availability of the Qdrant server
+static QDRANT_BREAKER: Mutex<QdrantBreaker> = Mutex::new(QdrantBreaker {
+    failures: 0,
+    opened_at: None,
+});
+
+#[derive(Debug)]
+struct QdrantBreaker {
+    // number of consecutive failures
+    failures: u32,
+    // the moment Qdrant was considered down
+    opened_at: Option<Instant>,
+}
+
+impl QdrantBreaker {
+    // closed, or half-open once the retry interval has elapsed, so that a request probes Qdrant again
+    fn available(&self, now: Instant) -> bool {
+        match self.opened_at {
+            Some(opened_at) => now.duration_since(opened_at) >= OUTAGE_RETRY_INTERVAL,
+            None => true,
+        }
+    }
+
+    fn record(&mut self, success: bool, now: Instant) {
+        match success {
+            true => {
+                if self.opened_at.is_some() {
+                    println!("    * Qdrant is reachable again. Retrieval resumed.");
+                }
+                self.failures = 0;
+                self.opened_at = None;
+            }
+            false => {
+                self.failures += 1;
+                if self.failures >= OUTAGE_FAILURE_THRESHOLD {
+                    if self.opened_at.is_none() {
+                        println!(
+                            "    * Qdrant failed {} times in a row. Retrieval suspended.",
+                            self.failures
+                        );
+                    }
+                    // restart the retry interval, also after a failed probe
+                    self.opened_at = Some(now);
+                }
+            }
+        }
+    }
+}
+
+/// Returns `false` if Qdrant is considered down and the retry interval has not elapsed yet.
+pub(crate) fn qdrant_available() -> bool {
+    match QDRANT_BREAKER.lock() {
+        Ok(breaker) => breaker.available(Instant::now()),
+        Err(_) => true,
+    }
+}
+
+/// Record the result of a request to Qdrant.
+pub(crate) fn record_qdrant_result(success: bool) {
+    if let Ok(mut breaker) = QDRANT_BREAKER.lock() {
+        breaker.record(success, Instant::now());
+    }
+}
+
+// dimensions of the vectors in the Qdrant collections, keyed by the url and the collection name
+static COLLECTION_DIMENSIONS: Lazy<Mutex<HashMap<(String, String), usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Get the dimension of the vectors in the Qdrant collection. Returns `None` if the collection does not exist or its dimension cannot be determined, and an error if Qdrant does not respond within the timeout.
+pub(crate) async fn collection_dimension(
+    qdrant_config: &QdrantConfig,
+    timeout: Duration,
+) -> Result<Option<usize>, String> {
+    let key = (
+        qdrant_config.url.clone(),
+        qdrant_config.collection_name.clone(),
+    );
+    if let Some(dim) = COLLECTION_DIMENSIONS
+        .lock()
+        .ok()
+        .and_then(|dimensions| dimensions.get(&key).copied())
+    {
+        return Ok(Some(dim));
+    }
+
+    let qdrant_client = Qdrant::new_with_url(qdrant_config.url.clone());
+    let info = tokio::time::timeout(
+        timeout,
+        qdrant_client.collection_info_api(qdrant_config.collection_name.as_str()),
+    )
+    .await
+    .map_err(|_| format!("No response within {}s.", timeout.as_secs()))?
+    .map_err(|e| e.to_string())?;
+    let dim = match info
+        .pointer("/result/config/params/vectors/size")
+        .and_then(|size| size.as_u64())
+    {
+        Some(dim) => dim as usize,
+        None => return Ok(None),
+    };
+
+    if let Ok(mut dimensions) = COLLECTION_DIMENSIONS.lock() {
+        dimensions.insert(key, dim);
+    }
+
+    Ok(Some(dim))
+}
+
+/// Forget the cached dimension of the Qdrant collection, for example, after the collection is (re)created.
+pub(crate) fn forget_collection_dimension(qdrant_config: &QdrantConfig) {
+    if let Ok(mut dimensions) = COLLECTION_DIMENSIONS.lock() {
+        dimensions.remove(&(
+            qdrant_config.url.clone(),
+            qdrant_config.collection_name.clone(),
+        ));
+    }
+}
+
+// retrieval results cached by the query and the Qdrant config
+static RETRIEVAL_CACHE: Lazy<Mutex<HashMap<RetrievalCacheKey, RetrievalCacheEntry>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RetrievalCacheKey {
+    // the query with collapsed whitespaces
+    query: String,
+    url: String,
+    collection_name: String,
+    limit: u64,
+    // bits of the score threshold, since `f32` is not hashable
+    score_threshold: u32,
+}
+
+impl RetrievalCacheKey {
+    fn new(query: &str, qdrant_config: &QdrantConfig) -> Self {
+        Self {
+            query: query.split_whitespace().collect::<Vec<_>>().join(" "),
+            url: qdrant_config.url.clone(),
+            collection_name: qdrant_config.collection_name.clone(),
+            limit: qdrant_config.limit,
+            score_threshold: qdrant_config.score_threshold.to_bits(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RetrievalCacheEntry {
+    points: Vec<RetrievedPoint>,
+    cached_at: Instant,
+}
+
+/// Get the cached retrieval result of the query. Returns `None` if the cache is disabled, or the result is not cached or has expired.
+pub(crate) fn cached_points(
+    query: &str,
+    qdrant_config: &QdrantConfig,
+    rag_config: &RagConfig,
+) -> Option<Vec<RetrievedPoint>> {
+    if rag_config.retrieval_cache_ttl == 0 {
+        return None;
+    }
+    let ttl = Duration::from_secs(rag_config.retrieval_cache_ttl);
+
+    let key = RetrievalCacheKey::new(query, qdrant_config);
+    let mut cache = RETRIEVAL_CACHE.lock().ok()?;
+    match cache.get(&key) {
+        Some(entry) if entry.cached_at.elapsed() < ttl => Some(entry.points.clone()),
+        Some(_) => {
+            cache.remove(&key);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Cache the retrieval result of the query. If the cache is full, the expired entries are evicted first, and then the oldest entry.
+pub(crate) fn cache_points(
+    query: &str,
+    qdrant_config: &QdrantConfig,
+    rag_config: &RagConfig,
+    points: &[RetrievedPoint],
+) {
+    if rag_config.retrieval_cache_ttl == 0 || rag_config.retrieval_cache_size == 0 {
+        return;
+    }
+    let ttl = Duration::from_secs(rag_config.retrieval_cache_ttl);
+
+    let key = RetrievalCacheKey::new(query, qdrant_config);
+    if let Ok(mut cache) = RETRIEVAL_CACHE.lock() {
+        if !cache.contains_key(&key) && cache.len() >= rag_config.retrieval_cache_size {
+            cache.retain(|_, entry| entry.cached_at.elapsed() < ttl);
+
+            if cache.len() >= rag_config.retrieval_cache_size {
+                let oldest = cache
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.cached_at)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    cache.remove(&oldest);
+                }
+            }
+        }
+
+        cache.insert(
+            key,
+            RetrievalCacheEntry {
+                points: points.to_vec(),
+                cached_at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Get the cached retrieval result of the query, or else run `retrieve`, which embeds the query and searches the Qdrant collection, and cache its result. `retrieve` returns `None` if the retrieval is skipped, which is not cached. The cache is not looked up if `use_cached` is false.
+pub(crate) async fn cached_or_retrieve<F, Fut>(
+    query: &str,
+    qdrant_config: &QdrantConfig,
+    rag_config: &RagConfig,
+    use_cached: bool,
+    retrieve: F,
+) -> Result<Option<Vec<RetrievedPoint>>, String>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Option<Vec<RetrievedPoint>>, String>>,
+{
+    if use_cached {
+        if let Some(points) = cached_points(query, qdrant_config, rag_config) {
+            println!(
+                "    * Retrieved {} point(s) from the retrieval cache",
+                points.len()
+            );
+            return Ok(Some(points));
+        }
+    }
+
+    let points = retrieve().await?;
+    if let Some(points) = &points {
+        cache_points(query, qdrant_config, rag_config, points);
+    }
+
+    Ok(points)
+}
+
+/// Drop the cached retrieval results of the Qdrant collection, for example, after new chunks are ingested into the collection.
+pub(crate) fn invalidate_retrieval_cache(qdrant_config: &QdrantConfig) {
+    if let Ok(mut cache) = RETRIEVAL_CACHE.lock() {
+        cache.retain(|key, _| {
+            key.url != qdrant_config.url || key.collection_name != qdrant_config.collection_name
+        });
+    }
+}
+
+/// Check that the Qdrant server is reachable within the timeout. Returns whether the collection exists, since a missing collection is created when the first document is ingested.
+pub(crate) async fn probe_qdrant(
+    qdrant_config: &QdrantConfig,
+    timeout: Duration,
+) -> Result<bool, String> {
+    let qdrant_client = Qdrant::new_with_url(qdrant_config.url.clone());
+    let info = tokio::time::timeout(
+        timeout,
+        qdrant_client.collection_info_api(qdrant_config.collection_name.as_str()),
+    )
+    .await
+    .map_err(|_| format!("No response within {}s.", timeout.as_secs()))?
+    .map_err(|e| e.to_string())?;
+
+    Ok(info.get("status").and_then(|status| status.as_str()) == Some("ok"))
+}
+
+/// Check the query embedding before searching the Qdrant collection with it.
+///
+/// The dimension of the collection is looked up within the timeout. The lookup is skipped while Qdrant is considered down, and its failures are recorded by the circuit breaker, so that the retrieval fails or degrades as usual.
+pub(crate) async fn check_query_embedding(
+    query_embedding: &[f32],
+    qdrant_config: &QdrantConfig,
+    timeout: Duration,
+) -> Result<(), String> {
+    if query_embedding.is_empty() {
+        return Err(
+            "Embedding stage failed: the embedding model returned an empty embedding for the user query."
+                .to_string(),
+        );
+    }
+
+    if !qdrant_available() {
+        return Ok(());
+    }
+    match collection_dimension(qdrant_config, timeout).await {
+        Ok(Some(dim)) => check_embedding_dimension(query_embedding, qdrant_config, dim),
+        Ok(None) => Ok(()),
+        Err(e) => {
+            println!(
+                "    * Failed to get the dimension of the Qdrant collection. {}",
+                e
+            );
+            record_qdrant_result(false);
+            Ok(())
+        }
+    }
+}
+
+// check the dimension of the query embedding against the dimension of the vectors in the collection
+fn check_embedding_dimension(
+    query_embedding: &[f32],
+    qdrant_config: &QdrantConfig,
+    dim: usize,
+) -> Result<(), String> {
+    match query_embedding.len() == dim {
+        true => Ok(()),
+        false => Err(format!(
+            "Embedding stage failed: the embedding of the user query has {} dimensions, while the vectors in the Qdrant collection `{}` have {} dimensions.",
+            query_embedding.len(),
+            qdrant_config.collection_name,
+            dim
+        )),
+    }
+}
+
+/// Detect the language of each chunk, and store its ISO 639-3 code in the `lang` field of the payload of the corresponding point.
+///
+/// The points are upserted again with the same ids, vectors and `source` fields, so that only the `lang` field is added. The `lang` field is skipped for a chunk whose language cannot be detected.
+pub(crate) async fn store_chunk_languages(
+    qdrant_config: &QdrantConfig,
+    chunks: &[String],
+    embeddings: &[EmbeddingObject],
+) -> Result<(), String> {
+    let points = language_points(chunks, embeddings);
+
+    let qdrant_client = Qdrant::new_with_url(qdrant_config.url.clone());
+    qdrant_client
+        .upsert_points(qdrant_config.collection_name.as_str(), points)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Build the points of the chunks with the `lang` field in their payload.
+fn language_points(chunks: &[String], embeddings: &[EmbeddingObject]) -> Vec<Point> {
+    let mut points = Vec::with_capacity(embeddings.len());
+    for embedding in embeddings {
+        let chunk = match chunks.get(embedding.index as usize) {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+
+        let mut payload = serde_json::Map::new();
+        payload.insert("source".to_string(), chunk.clone().into());
+        if let Some(info) = whatlang::detect(chunk) {
+            payload.insert("lang".to_string(), info.lang().code().into());
+        }
+
+        points.push(Point {
+            id: PointId::Num(embedding.index),
+            vector: embedding.embedding.iter().map(|x| *x as f32).collect(),
+            payload: Some(payload),
+        });
+    }
+    points
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn qdrant_config() -> QdrantConfig {
+        QdrantConfig {
+            url: "http://localhost:6333".to_string(),
+            collection_name: "default".to_string(),
+            limit: 5,
+            score_threshold: 0.4,
+        }
+    }
+
+    fn rag_config(retrieval_cache_ttl: u64, retrieval_cache_size: usize) -> RagConfig {
+        let model = |name: &str, ty: &str| crate::ModelConfig {
+            name: name.to_string(),
+            alias: name.to_string(),
+            ty: ty.to_string(),
+            prompt_template: chat_prompts::PromptTemplateType::Llama2Chat,
+            n_predict: 1024,
+            reverse_prompt: None,
+            n_gpu_layers: 100,
+            ctx_size: 4096,
+            batch_size: 512,
+            temperature: 1.0,
+            top_p: 1.0,
+            repeat_penalty: 1.1,
+            presence_penalty: 0.0,
+            frequency_penalty: 0.0,
+        };
+        RagConfig {
+            chat_model: model("default", "chat"),
+            embedding_model: model("embedding", "embedding"),
+            policy: chat_prompts::MergeRagContextPolicy::SystemMessage,
+            log_chunk_ids_only: false,
+            grounding_score: None,
+            qdrant_outage_behavior: crate::QdrantOutageBehavior::Fail,
+            stream_retrieval_events: false,
+            max_stop_sequences: 5,
+            require_model_field: false,
+            max_batch_requests: 8,
+            enable_mmr: false,
+            mmr_lambda: 0.5,
+            timing_headers: false,
+            detect_chunk_language: false,
+            max_response_bytes: None,
+            min_chunk_chars: 0,
+            retrieval_cache_ttl,
+            retrieval_cache_size,
+            max_embedding_inputs: 2048,
+            embedding_batch_size: 32,
+            allow_raw_prompt: false,
+            debug_retrieval: false,
+            qdrant_connect_timeout: 5,
+            max_qdrant_limit: 100,
+        }
+    }
+
+    fn point(id: u64, score: f32, vector: Vec<f32>) -> RetrievedPoint {
+        RetrievedPoint {
+            id: id.to_string(),
+            source: format!("\"chunk {}\"", id),
+            score,
+            vector: Some(vector),
+        }
+    }
+
+    #[test]
+    fn test_sort_scored_points() {
+        let scored_point = |id: PointId, score: f32| ScoredPoint {
+            id,
+            vector: None,
+            payload: None,
+            score,
+        };
+        let mut scored_points = vec![
+            scored_point(PointId::Uuid("b".to_string()), 0.8),
+            scored_point(PointId::Num(7), 0.8),
+            scored_point(PointId::Num(3), 0.5),
+            scored_point(PointId::Uuid("a".to_string()), 0.8),
+            scored_point(PointId::Num(2), 0.8),
+            scored_point(PointId::Num(9), 0.9),
+        ];
+
+        sort_scored_points(&mut scored_points);
+        let ids: Vec<String> = scored_points
+            .iter()
+            .map(|point| point_id_to_string(&point.id))
+            .collect();
+        // equal scores are ordered by id, numeric ids first
+        assert_eq!(ids, vec!["9", "2", "7", "a", "b", "3"]);
+    }
+
+    #[test]
+    fn test_mmr_select() {
+        // the second point duplicates the first one
+        let candidates = vec![
+            point(1, 0.9, vec![1.0, 0.0]),
+            point(2, 0.89, vec![1.0, 0.0]),
+            point(3, 0.7, vec![0.0, 1.0]),
+        ];
+
+        // relevance only: the top-N points
+        let ids: Vec<_> = mmr_select(candidates.clone(), 2, 1.0)
+            .into_iter()
+            .map(|point| point.id)
+            .collect();
+        assert_eq!(ids, ["1", "2"]);
+
+        // the duplicate gives way to a less relevant but diverse point
+        let ids: Vec<_> = mmr_select(candidates.clone(), 2, 0.5)
+            .into_iter()
+            .map(|point| point.id)
+            .collect();
+        assert_eq!(ids, ["1", "3"]);
+
+        // fewer candidates than requested
+        assert_eq!(mmr_select(candidates, 5, 0.5).len(), 3);
+    }
+
+    #[test]
+    fn test_select_points_explanations() {
+        // the threshold is applied to the candidates while explaining
+        let candidates = vec![
+            point(1, 0.9, vec![1.0, 0.0]),
+            point(2, 0.85, vec![1.0, 0.0]),
+            point(3, 0.5, vec![0.0, 1.0]),
+            point(4, 0.3, vec![0.0, 1.0]),
+        ];
+        let qdrant_config = QdrantConfig {
+            limit: 2,
+            ..qdrant_config()
+        };
+
+        // without MMR, only the threshold drops chunks
+        let mut explanations = vec![];
+        let selected = select_points(
+            candidates.clone(),
+            &qdrant_config,
+            0,
+            None,
+            Some(&mut explanations),
+        );
+        assert_eq!(selected.len(), 3);
+        assert!(explanations.iter().all(|e| e.survived_mmr.is_none()));
+        assert_eq!(explanations[3].dropped_by, Some(DropReason::ScoreThreshold));
+        assert!(!explanations[3].passed_threshold);
+
+        // with MMR, the duplicate above the threshold is dropped by MMR
+        let mut explanations = vec![];
+        let ids: Vec<_> = select_points(
+            candidates,
+            &qdrant_config,
+            0,
+            Some(0.5),
+            Some(&mut explanations),
+        )
+        .into_iter()
+        .map(|point| point.id)
+        .collect();
+        assert_eq!(ids, ["1", "3"]);
+        let explained: Vec<_> = explanations
+            .iter()
+            .map(|e| {
+                (
+                    e.id.as_str(),
+                    e.passed_threshold,
+                    e.survived_mmr,
+                    e.dropped_by,
+                )
+            })
+            .collect();
+        assert_eq!(
+            explained,
+            [
+                ("1", true, Some(true), None),
+                ("2", true, Some(false), Some(DropReason::Mmr)),
+                ("3", true, Some(true), None),
+                ("4", false, None, Some(DropReason::ScoreThreshold)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_points_min_chunk_chars() {
+        let short = |id: u64, score: f32, vector: Vec<f32>| RetrievedPoint {
+            source: "\"# \"".to_string(),
+            ..point(id, score, vector)
+        };
+        let candidates = vec![
+            short(1, 0.9, vec![1.0, 0.0]),
+            point(2, 0.8, vec![0.0, 1.0]),
+            point(3, 0.7, vec![1.0, 1.0]),
+            short(4, 0.65, vec![0.0, 1.0]),
+            point(5, 0.6, vec![1.0, 0.0]),
+        ];
+        let qdrant_config = QdrantConfig {
+            limit: 2,
+            ..qdrant_config()
+        };
+
+        // the short chunks do not take the slots of the selection
+        let mut explanations = vec![];
+        let ids: Vec<_> = select_points(
+            candidates.clone(),
+            &qdrant_config,
+            5,
+            Some(1.0),
+            Some(&mut explanations),
+        )
+        .into_iter()
+        .map(|point| point.id)
+        .collect();
+        assert_eq!(ids, ["2", "3"]);
+        let dropped_by: Vec<_> = explanations.iter().map(|e| e.dropped_by).collect();
+        assert_eq!(
+            dropped_by,
+            [
+                Some(DropReason::MinChunkChars),
+                None,
+                None,
+                Some(DropReason::MinChunkChars),
+                Some(DropReason::Mmr),
+            ]
+        );
+        assert_eq!(explanations[0].survived_mmr, None);
+        assert_eq!(explanations[4].survived_mmr, Some(false));
+
+        // nothing remains if all chunks are short
+        assert!(select_points(candidates, &qdrant_config, 100, Some(1.0), None).is_empty());
+    }
+
+    #[test]
+    fn test_retrieval_cache() {
+        // the cache is shared by the tests, so the collections are specific to this test
+        let papers = QdrantConfig {
+            collection_name: "test_retrieval_cache_papers".to_string(),
+            ..qdrant_config()
+        };
+        let notes = QdrantConfig {
+            collection_name: "test_retrieval_cache_notes".to_string(),
+            ..qdrant_config()
+        };
+        let disabled = rag_config(0, 16);
+        let rag_config = rag_config(60, 16);
+        let points = vec![point(1, 0.9, vec![1.0, 0.0])];
+
+        assert!(cached_points("What is RAG?", &papers, &rag_config).is_none());
+        cache_points("What is RAG?", &papers, &rag_config, &points);
+        cache_points("What is RAG?", &notes, &rag_config, &points);
+
+        // identical queries up to whitespaces hit the cache
+        let cached = cached_points("  What is\tRAG? ", &papers, &rag_config).unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].id, "1");
+        // a different search misses it
+        let limited = QdrantConfig {
+            limit: 1,
+            ..papers.clone()
+        };
+        assert!(cached_points("What is RAG?", &limited, &rag_config).is_none());
+        // a disabled cache is never hit
+        assert!(cached_points("What is RAG?", &papers, &disabled).is_none());
+
+        // the ingestion into a collection only drops its own results
+        invalidate_retrieval_cache(&papers);
+        assert!(cached_points("What is RAG?", &papers, &rag_config).is_none());
+        assert!(cached_points("What is RAG?", &notes, &rag_config).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cached_or_retrieve() {
+        // the cache is shared by the tests, so the collection is specific to this test
+        let qdrant_config = QdrantConfig {
+            collection_name: "test_cached_or_retrieve".to_string(),
+            ..qdrant_config()
+        };
+        let disabled = rag_config(0, 16);
+        let rag_config = rag_config(60, 16);
+        let retrievals = std::cell::Cell::new(0);
+        // stands for the embedding of the query and the Qdrant search
+        let retrieve = |result: Result<Option<Vec<RetrievedPoint>>, String>| {
+            retrievals.set(retrievals.get() + 1);
+            async move { result }
+        };
+        let points = || Ok(Some(vec![point(1, 0.9, vec![1.0, 0.0])]));
+
+        // failed and skipped retrievals are not cached
+        let query = "What is RAG?";
+        assert!(
+            cached_or_retrieve(query, &qdrant_config, &rag_config, true, || {
+                retrieve(Err("Qdrant is down".to_string()))
+            })
+            .await
+            .is_err()
+        );
+        let skipped = cached_or_retrieve(query, &qdrant_config, &rag_config, true, || {
+            retrieve(Ok(None))
+        })
+        .await;
+        assert!(skipped.unwrap().is_none());
+        assert_eq!(retrievals.get(), 2);
+
+        // neither the embedding nor the search runs again for an identical query
+        let retrieved = cached_or_retrieve(query, &qdrant_config, &rag_config, true, || {
+            retrieve(points())
+        })
+        .await;
+        assert_eq!(retrieved.unwrap().unwrap().len(), 1);
+        let cached =
+            cached_or_retrieve(" What is  RAG?", &qdrant_config, &rag_config, true, || {
+                retrieve(points())
+            })
+            .await;
+        assert_eq!(cached.unwrap().unwrap()[0].id, "1");
+        assert_eq!(retrievals.get(), 3);
+
+        // the cache is not looked up while explaining, nor if it is disabled
+        let _ = cached_or_retrieve(query, &qdrant_config, &rag_config, false, || {
+            retrieve(points())
+        })
+        .await;
+        let _ = cached_or_retrieve(
+            query,
+            &qdrant_config,
+            &disabled,
+            true,
+            || retrieve(points()),
+        )
+        .await;
+        assert_eq!(retrievals.get(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_check_empty_query_embedding() {
+        // an empty embedding is rejected before Qdrant is contacted
+        let e = check_query_embedding(&[], &qdrant_config(), Duration::from_secs(1))
+            .await
+            .unwrap_err();
+        assert!(e.starts_with("Embedding stage failed"));
+        assert!(e.contains("empty embedding"));
+    }
+
+    #[test]
+    fn test_check_embedding_dimension() {
+        assert!(check_embedding_dimension(&[0.1; 4], &qdrant_config(), 4).is_ok());
+
+        let e = check_embedding_dimension(&[0.1; 3], &qdrant_config(), 4).unwrap_err();
+        assert!(e.starts_with("Embedding stage failed"));
+        assert!(e.contains("has 3 dimensions"));
+        assert!(e.contains("have 4 dimensions"));
+    }
+
+    #[test]
+    fn test_qdrant_breaker_cycle() {
+        let mut breaker = QdrantBreaker {
+            failures: 0,
+            opened_at: None,
+        };
+        let start = Instant::now();
+
+        // closed until the failure threshold is reached
+        for _ in 1..OUTAGE_FAILURE_THRESHOLD {
+            breaker.record(false, start);
+            assert!(breaker.available(start));
+        }
+        breaker.record(false, start);
+        assert!(!breaker.available(start));
+
+        // half-open after the retry interval, and open again after a failed probe
+        let probe = start + OUTAGE_RETRY_INTERVAL;
+        assert!(breaker.available(probe));
+        breaker.record(false, probe);
+        assert!(!breaker.available(probe));
+
+        // closed after a successful probe
+        let probe = probe + OUTAGE_RETRY_INTERVAL;
+        assert!(breaker.available(probe));
+        breaker.record(true, probe);
+        assert!(breaker.available(probe));
+        assert_eq!(breaker.failures, 0);
+        assert!(breaker.opened_at.is_none());
+    }
+
+    #[test]
+    fn test_language_points() {
+        let chunks = vec![
+            "The quick brown fox jumps over the lazy dog near the river bank.".to_string(),
+            "Le renard brun saute par-dessus le chien paresseux au bord de la rivière.".to_string(),
+            "".to_string(),
+        ];
+        let embeddings: Vec<EmbeddingObject> = (0..3)
+            .map(|index| EmbeddingObject {
+                index,
+                object: "embedding".to_string(),
+                embedding: vec![index as f64, 1.0],
+            })
+            .collect();
+
+        let points = language_points(&chunks, &embeddings);
+        assert_eq!(points.len(), 3);
+        let langs: Vec<Option<&str>> = points
+            .iter()
+            .map(|point| point.payload.as_ref().unwrap().get("lang")?.as_str())
+            .collect();
+        assert_eq!(langs, vec![Some("eng"), Some("fra"), None]);
+
+        // the ids, vectors and sources are kept
+        assert!(matches!(points[1].id, PointId::Num(1)));
+        assert_eq!(points[1].vector, vec![1.0, 1.0]);
+        assert_eq!(
+            points[1].payload.as_ref().unwrap()["source"].as_str(),
+            Some(chunks[1].as_str())
+        );
+    }
+}
@@ -32,6 +32,18 @@ pub(crate) fn is_valid_url(url: &str) -> bool {
     Url::parse(url).is_ok()
 }
 
+/// Compare two byte strings in constant time with respect to their contents, so that the comparison does not leak how many leading bytes match.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
 pub(crate) fn log(msg: impl std::fmt::Display) {
     println!("{}", msg);
 }
@@ -39,3 +51,21 @@ pub(crate) fn log(msg: impl std::fmt::Display) {
 pub(crate) fn gen_chat_id() -> String {
     format!("chatcmpl-{}", uuid::Uuid::new_v4())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"sk-secret", b"sk-secret"));
+        assert!(constant_time_eq(b"", b""));
+
+        // mismatches at the first and the last byte
+        assert!(!constant_time_eq(b"sk-secret", b"xk-secret"));
+        assert!(!constant_time_eq(b"sk-secret", b"sk-secreT"));
+        // a prefix of the key
+        assert!(!constant_time_eq(b"sk-secret", b"sk-sec"));
+        assert!(!constant_time_eq(b"sk", b""));
+    }
+}